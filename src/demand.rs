@@ -0,0 +1,315 @@
+use crate::economy::fare;
+use crate::network::Mode;
+use crate::state::MapState;
+use crate::suitability::flatness;
+use crate::train::Train;
+use bevy::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+// Cells around a station whose people and resources it serves.
+pub const CATCHMENT_RADIUS: usize = 16;
+// People living on an ideal cell: low and flat.
+pub const PEOPLE_PER_CELL: f32 = 10.0;
+// Tonnes of goods from an ideal cell: high and rugged.
+pub const RESOURCES_PER_CELL: f32 = 10.0;
+// Trips per second for two catchments of one at one cell apart.
+pub const DEMAND_RATE: f32 = 0.0025;
+// Stations stop attracting more of a trip once this many are waiting.
+pub const MAX_WAITING: f32 = 200.0;
+// Passengers or tonnes a train carries.
+pub const TRAIN_CAPACITY: f32 = 100.0;
+pub const ROAD_VEHICLE_CAPACITY: f32 = 30.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Cargo {
+    Passengers,
+    Freight,
+}
+
+// Trips a train carries to one station, and what they pay on arrival.
+#[derive(Clone)]
+pub struct Load {
+    pub destination: (usize, usize),
+    pub amount: f32,
+    pub revenue: f32,
+}
+
+// What a station's surroundings produce: people for passengers, resources
+// for freight.
+#[derive(Clone, Copy, Default)]
+pub struct Catchment {
+    pub people: f32,
+    pub resources: f32,
+}
+
+impl Catchment {
+    fn of(&self, cargo: Cargo) -> f32 {
+        match cargo {
+            Cargo::Passengers => self.people,
+            Cargo::Freight => self.resources,
+        }
+    }
+}
+
+// Trips waiting at one station, by destination and cargo.
+pub type Waiting = BTreeMap<((usize, usize), Cargo), f32>;
+
+// What the trips from one station to another would pay per second.
+pub type DemandMatrix = BTreeMap<((usize, usize), (usize, usize)), f32>;
+
+// Trips waiting at each station for each destination, and those delivered.
+#[derive(Resource, Default)]
+pub struct Demand {
+    pub waiting: HashMap<(usize, usize), Waiting>,
+    pub delivered: BTreeMap<Cargo, f32>,
+    pub delivered_to: HashMap<(usize, usize), BTreeMap<Cargo, f32>>,
+    catchments: HashMap<(usize, usize), Catchment>,
+}
+
+impl Demand {
+    pub fn catchment(&mut self, map_state: &MapState, station: (usize, usize)) -> Catchment {
+        *self
+            .catchments
+            .entry(station)
+            .or_insert_with(|| catchment(map_state, station))
+    }
+
+    // Drops the cached catchments, for when the land around stations changed.
+    pub fn forget_catchments(&mut self) {
+        self.catchments.clear();
+    }
+
+    // Gravity model: every pair of stations attracts trips in proportion to
+    // both catchments and inversely to the square of their distance.
+    pub fn generate(&mut self, map_state: &MapState, seconds: f32) {
+        let mut stations = map_state
+            .network
+            .stations
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        stations.sort();
+        let stations_now = &map_state.network.stations;
+        self.waiting
+            .retain(|station, _| stations_now.contains(station));
+        for waiting in self.waiting.values_mut() {
+            waiting.retain(|(to, _), _| stations_now.contains(to));
+        }
+        for &from in stations.iter() {
+            let from_catchment = self.catchment(map_state, from);
+            for &to in stations.iter() {
+                if from == to {
+                    continue;
+                }
+                let to_catchment = self.catchment(map_state, to);
+                for cargo in [Cargo::Passengers, Cargo::Freight] {
+                    let rate = rate(&from_catchment, &to_catchment, from, to, cargo);
+                    let waiting = self
+                        .waiting
+                        .entry(from)
+                        .or_default()
+                        .entry((to, cargo))
+                        .or_default();
+                    *waiting = (*waiting + rate * seconds).min(MAX_WAITING);
+                }
+            }
+        }
+    }
+
+    // The fares every pair of stations would earn each second if all their
+    // trips were carried.
+    pub fn matrix(&mut self, map_state: &MapState) -> DemandMatrix {
+        let stations = map_state
+            .network
+            .stations
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut matrix = DemandMatrix::new();
+        for &from in stations.iter() {
+            let from_catchment = self.catchment(map_state, from);
+            for &to in stations.iter() {
+                if from == to {
+                    continue;
+                }
+                let to_catchment = self.catchment(map_state, to);
+                let revenue = [Cargo::Passengers, Cargo::Freight]
+                    .into_iter()
+                    .map(|cargo| {
+                        rate(&from_catchment, &to_catchment, from, to, cargo)
+                            * fare(cargo, from, to)
+                    })
+                    .sum();
+                matrix.insert((from, to), revenue);
+            }
+        }
+        matrix
+    }
+
+    // Unloads what a train has for the station it stands at, then loads
+    // trips waiting there for stations it calls at, as far as it has room.
+    // Returns what the delivered trips paid.
+    pub fn call(&mut self, station: (usize, usize), train: &mut Train) -> f32 {
+        let mut delivered = 0.0;
+        let mut revenue = 0.0;
+        train.load.retain(|load| {
+            if load.destination != station {
+                return true;
+            }
+            delivered += load.amount;
+            revenue += load.revenue;
+            false
+        });
+        if delivered > 0.0 {
+            *self.delivered.entry(train.cargo).or_default() += delivered;
+            *self
+                .delivered_to
+                .entry(station)
+                .or_default()
+                .entry(train.cargo)
+                .or_default() += delivered;
+        }
+        let Some(waiting) = self.waiting.get_mut(&station) else {
+            return revenue;
+        };
+        let calls = train.calls();
+        let capacity = match train.mode {
+            Mode::Rail => TRAIN_CAPACITY,
+            Mode::Road => ROAD_VEHICLE_CAPACITY,
+        };
+        for &destination in calls.iter().filter(|&&call| call != station) {
+            let room = capacity - train.load.iter().map(|load| load.amount).sum::<f32>();
+            let Some(count) = waiting.get_mut(&(destination, train.cargo)) else {
+                continue;
+            };
+            let boarding = count.floor().min(room.floor());
+            if boarding <= 0.0 {
+                continue;
+            }
+            *count -= boarding;
+            let paid = boarding * fare(train.cargo, station, destination);
+            match train
+                .load
+                .iter_mut()
+                .find(|load| load.destination == destination)
+            {
+                Some(load) => {
+                    load.amount += boarding;
+                    load.revenue += paid;
+                }
+                None => train.load.push(Load {
+                    destination,
+                    amount: boarding,
+                    revenue: paid,
+                }),
+            }
+        }
+        revenue
+    }
+
+    pub fn waiting_at(&self, station: (usize, usize), cargo: Cargo) -> f32 {
+        self.waiting
+            .get(&station)
+            .map(|waiting| {
+                waiting
+                    .iter()
+                    .filter(|((_, waiting_cargo), _)| *waiting_cargo == cargo)
+                    .map(|(_, count)| count.floor())
+                    .sum()
+            })
+            .unwrap_or(0.0)
+    }
+}
+
+// Trips per second from one station to another.
+fn rate(
+    from_catchment: &Catchment,
+    to_catchment: &Catchment,
+    from: (usize, usize),
+    to: (usize, usize),
+    cargo: Cargo,
+) -> f32 {
+    let distance_squared = (from.0.abs_diff(to.0).pow(2) + from.1.abs_diff(to.1).pow(2)) as f32;
+    DEMAND_RATE * from_catchment.of(cargo) * to_catchment.of(cargo) / distance_squared
+}
+
+fn catchment(map_state: &MapState, (row, col): (usize, usize)) -> Catchment {
+    let dijkstra = &map_state.dijkstra;
+    let mut catchment = Catchment::default();
+    let rows =
+        row.saturating_sub(CATCHMENT_RADIUS)..(row + CATCHMENT_RADIUS + 1).min(dijkstra.height);
+    for r in rows {
+        let cols =
+            col.saturating_sub(CATCHMENT_RADIUS)..(col + CATCHMENT_RADIUS + 1).min(dijkstra.width);
+        for c in cols {
+            if r.abs_diff(row).pow(2) + c.abs_diff(col).pow(2) > CATCHMENT_RADIUS.pow(2)
+                || dijkstra.is_water[r][c]
+            {
+                continue;
+            }
+            let height = map_state.normalized_height(r, c);
+            let flatness = flatness(dijkstra, (r, c));
+            catchment.people += PEOPLE_PER_CELL * (1.0 - height) * flatness;
+            catchment.resources += RESOURCES_PER_CELL * height * (1.0 - flatness);
+        }
+    }
+    catchment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // A 1% slope up to the east with stations in the west, middle and
+    // east.
+    fn map_state() -> MapState {
+        let heights = (0..64)
+            .map(|_| (0..64).map(|col| col as f32 * 0.0001).collect())
+            .collect();
+        let mut map_state = MapState::from_heights(heights);
+        let network = Arc::make_mut(&mut map_state.network);
+        for station in [(32, 8), (32, 24), (32, 56)] {
+            network.place_station(station);
+        }
+        map_state
+    }
+
+    #[test]
+    fn rate_falls_with_the_square_of_distance() {
+        let catchment = Catchment {
+            people: 100.0,
+            resources: 10.0,
+        };
+        let near = rate(&catchment, &catchment, (0, 0), (0, 10), Cargo::Passengers);
+        let far = rate(&catchment, &catchment, (0, 0), (0, 20), Cargo::Passengers);
+        assert!((near - DEMAND_RATE * 100.0).abs() < 1e-6);
+        assert!((near - 4.0 * far).abs() < 1e-6);
+        let freight = rate(&catchment, &catchment, (0, 0), (0, 10), Cargo::Freight);
+        assert!((freight - DEMAND_RATE).abs() < 1e-6);
+    }
+
+    #[test]
+    fn matrix_covers_every_pair_of_stations() {
+        let map_state = map_state();
+        let matrix = Demand::default().matrix(&map_state);
+        assert_eq!(matrix.len(), 6);
+        assert!(matrix.values().all(|&revenue| revenue > 0.0));
+        let there = matrix[&((32, 8), (32, 24))];
+        let back = matrix[&((32, 24), (32, 8))];
+        assert!((there - back).abs() < 1e-3 * there);
+    }
+
+    #[test]
+    fn generate_stops_at_the_waiting_limit() {
+        let map_state = map_state();
+        let mut demand = Demand::default();
+        let key = ((32, 24), Cargo::Passengers);
+        demand.generate(&map_state, 0.01);
+        let first = demand.waiting[&(32, 8)][&key];
+        demand.generate(&map_state, 1e9);
+        let full = demand.waiting[&(32, 8)][&key];
+        assert!(first < full);
+        assert_eq!(full, MAX_WAITING);
+    }
+}
@@ -1,8 +1,14 @@
+use crate::network::{Mode, Network, RoutePlan, TIERS};
+use crate::planner::{PlanJob, PlannedLink, autoplan};
+use crate::scale::Scale;
+use crate::settlement::{Growth, grow};
+use crate::state::extract_water;
+use crate::zones::{ZONE_PENALTY, Zone};
 use crossbeam_channel::{Receiver, Sender};
-use ordered_float::{OrderedFloat, Pow};
+use ordered_float::OrderedFloat;
 use priority_queue::PriorityQueue;
-use rand::prelude::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct Dijkstra {
@@ -10,170 +16,584 @@ pub struct Dijkstra {
     pub height: usize,
     pub height_map: Vec<Vec<f32>>,
     pub is_water: Vec<Vec<bool>>,
-    pub road_level: Vec<Vec<i32>>,
-    pub house_level: Vec<Vec<i32>>,
+    // The map's scale, for quoting costs per kilometre.
+    pub scale: Scale,
+    pub rail_costs: CostModel,
+    pub road_costs: CostModel,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct CostModel {
+    // Per kilometre of track.
+    pub step_on_road: f32,
+    pub build_road: f32,
+    pub build_bridge: f32,
+    // Weight of the steepness of a step, the metres it climbs times its
+    // length. The climb cost is the square of the weighted steepness, so
+    // doubling this quadruples it.
+    pub steepness_weight: f32,
+    // Travelling on existing infrastructure of each tier is cheaper the
+    // higher it is, and raising a kilometre by one tier costs `upgrade`.
+    pub tier_step: [f32; TIERS],
+    pub upgrade: f32,
+}
+
+impl CostModel {
+    pub fn rail() -> Self {
+        CostModel {
+            step_on_road: 100.0,
+            build_road: 300.0,
+            build_bridge: 1000.0,
+            steepness_weight: 3.0,
+            tier_step: [1.0, 0.8, 0.6],
+            upgrade: 200.0,
+        }
+    }
+
+    // Roads are cheaper to build and cope with steeper grades.
+    pub fn road() -> Self {
+        CostModel {
+            step_on_road: 100.0,
+            build_road: 150.0,
+            build_bridge: 600.0,
+            steepness_weight: 1.5,
+            tier_step: [1.0, 0.7, 0.5],
+            upgrade: 100.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepKind {
+    ExistingTrack,
+    NewTrack,
+    Bridge,
+}
+
+pub type JobId = u64;
+
+pub type Segment = ((usize, usize), (usize, usize));
+
+// Maximum number of route jobs that may be queued or running at once.
+pub const MAX_PENDING_JOBS: usize = 4;
+
+// How many nodes the search expands between checks for cancellation.
+const INTERRUPT_CHECK_INTERVAL: usize = 256;
+
+// How many nodes the search expands between two frontier snapshots.
+const SNAPSHOT_INTERVAL: usize = 4096;
+
+// How many alternative routes are offered for each job.
+pub const ALTERNATIVES: usize = 3;
+
+// Step cost multiplier on cells used by previously found alternatives.
+const ALTERNATIVE_PENALTY: f32 = 1.5;
+
+// Alternatives sharing more than this fraction of cells count as the same route.
+const MAX_ALTERNATIVE_OVERLAP: f32 = 0.6;
+
+// Jobs carry a snapshot of the network as it was when they were issued.
 #[derive(Clone)]
-pub struct DijkstraCommand {
+pub struct RouteJob {
+    pub id: JobId,
+    pub mode: Mode,
     pub a: (usize, usize),
     pub b: (usize, usize),
+    pub network: Arc<Network>,
+}
+
+pub enum DijkstraCommand {
+    Connect(RouteJob),
+    // Cancels the running job and everything queued, then runs this one.
+    Supersede(RouteJob),
+    // Plans and builds links between stations automatically.
+    AutoPlan(PlanJob),
+    Cancel(JobId),
+    CancelAll,
+    // Runs one step of settlement growth on this snapshot once no route
+    // jobs are waiting.
+    Grow(Arc<Network>),
+    // Fills the reshaped ground with water and plans on it from then on,
+    // before starting on anything else.
+    Terrain(Reshape),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Running,
+    Done,
+    NotFound,
+    Cancelled,
+}
+
+// Lengths in kilometres, climb and descent in metres and the grade in
+// percent.
+#[derive(Clone, Debug, Default)]
+pub struct RouteBreakdown {
+    pub length: f32,
+    pub existing_length: f32,
+    pub new_length: f32,
+    pub bridge_length: f32,
+    pub climb: f32,
+    pub descent: f32,
+    pub max_grade: f32,
+    pub existing_cost: f32,
+    pub new_track_cost: f32,
+    pub bridge_cost: f32,
+    pub climb_cost: f32,
+}
+
+impl RouteBreakdown {
+    pub fn total_cost(&self) -> f32 {
+        self.existing_cost + self.new_track_cost + self.bridge_cost + self.climb_cost
+    }
+}
+
+// The ground after the given reshape.
+pub struct Reshape {
+    pub id: u64,
+    pub ground: Vec<Vec<f32>>,
+}
+
+// The heights with lakes levelled and the water of a reshaped ground.
+pub struct Water {
+    pub id: u64,
+    pub height_map: Vec<Vec<f32>>,
+    pub is_water: Vec<Vec<bool>>,
 }
 
-pub struct DijkstraUpdate {
-    pub path: Vec<((usize, usize), (usize, usize))>,
-    pub houses: Vec<(usize, usize)>,
+#[derive(Clone)]
+pub struct Alternatives {
+    pub id: JobId,
+    pub options: Vec<RoutePlan>,
+}
+
+// Cells expanded since the previous snapshot, and the whole current frontier.
+pub struct SearchSnapshot {
+    pub visited: Vec<(usize, usize)>,
+    pub frontier: Vec<(usize, usize)>,
+}
+
+pub enum DijkstraUpdate {
+    Job(JobId, JobStatus),
+    Search(SearchSnapshot),
+    Alternatives(Alternatives),
+    // What one step of settlement growth built, possibly nothing.
+    Growth(Vec<Growth>),
+    // A link the autoplanner decided to build.
+    Planned(PlannedLink),
+    // The water of reshaped ground, as the worker now plans with it.
+    Water(Water),
+}
+
+// Cells covered by a segment, walking diagonally first and then straight.
+pub fn segment_cells(start: (usize, usize), end: (usize, usize)) -> Vec<(usize, usize)> {
+    let (mut row, mut col) = start;
+    let mut cells = vec![start];
+    while (row, col) != end {
+        row = (row as isize + (end.0 as isize - row as isize).signum()) as usize;
+        col = (col as isize + (end.1 as isize - col as isize).signum()) as usize;
+        cells.push((row, col));
+    }
+    cells
+}
+
+struct JobQueue<'a> {
+    command_rx: &'a Receiver<DijkstraCommand>,
+    tx: Sender<DijkstraUpdate>,
+    pending: VecDeque<Work>,
+    grow: Option<Arc<Network>>,
+}
+
+// What the worker does next: a route job, a planning job, a step of
+// settlement growth, or switching to reshaped terrain.
+enum Work {
+    Route(RouteJob),
+    Plan(PlanJob),
+    Grow(Arc<Network>),
+    Terrain(Reshape),
+}
+
+impl Work {
+    fn id(&self) -> Option<JobId> {
+        match self {
+            Work::Route(job) => Some(job.id),
+            Work::Plan(job) => Some(job.id),
+            Work::Grow(_) | Work::Terrain(_) => None,
+        }
+    }
+}
+
+impl JobQueue<'_> {
+    // Returns true if the running job has to be abandoned.
+    fn handle(&mut self, command: DijkstraCommand, running: Option<JobId>) -> bool {
+        match command {
+            DijkstraCommand::Connect(job) => {
+                self.pending.push_back(Work::Route(job));
+                false
+            }
+            DijkstraCommand::Supersede(job) => {
+                self.cancel_pending();
+                self.pending.push_back(Work::Route(job));
+                running.is_some()
+            }
+            DijkstraCommand::AutoPlan(job) => {
+                self.pending.push_back(Work::Plan(job));
+                false
+            }
+            DijkstraCommand::Cancel(id) => {
+                if running == Some(id) {
+                    return true;
+                }
+                if let Some(index) = self.pending.iter().position(|job| job.id() == Some(id)) {
+                    self.pending.remove(index);
+                    let _ = self.tx.send(DijkstraUpdate::Job(id, JobStatus::Cancelled));
+                }
+                false
+            }
+            DijkstraCommand::CancelAll => {
+                self.cancel_pending();
+                running.is_some()
+            }
+            DijkstraCommand::Grow(network) => {
+                self.grow = Some(network);
+                false
+            }
+            DijkstraCommand::Terrain(reshape) => {
+                self.pending.push_front(Work::Terrain(reshape));
+                false
+            }
+        }
+    }
+
+    fn cancel_pending(&mut self) {
+        for id in self.pending.drain(..).filter_map(|job| job.id()) {
+            let _ = self.tx.send(DijkstraUpdate::Job(id, JobStatus::Cancelled));
+        }
+    }
+
+    fn next(&mut self) -> Option<Work> {
+        loop {
+            if let Some(work) = self.pending.pop_front() {
+                return Some(work);
+            }
+            if let Some(network) = self.grow.take() {
+                return Some(Work::Grow(network));
+            }
+            let command = self.command_rx.recv().ok()?;
+            self.handle(command, None);
+        }
+    }
+
+    fn interrupted(&mut self, running: JobId) -> bool {
+        let mut stop = false;
+        while let Ok(command) = self.command_rx.try_recv() {
+            stop |= self.handle(command, Some(running));
+        }
+        stop
+    }
 }
 
 impl Dijkstra {
+    // Level dry land, for tests.
+    #[cfg(test)]
+    pub fn flat(width: usize, height: usize) -> Self {
+        Dijkstra {
+            width,
+            height,
+            height_map: vec![vec![0.0; width]; height],
+            is_water: vec![vec![false; width]; height],
+            scale: Scale::default(),
+            rail_costs: CostModel::rail(),
+            road_costs: CostModel::road(),
+        }
+    }
+
     pub fn connect_selected(
         &mut self,
         command_rx: &Receiver<DijkstraCommand>,
         tx: Sender<DijkstraUpdate>,
     ) {
-        while let Ok(command) = command_rx.recv() {
-            println!("Received command to connect {:?} to {:?}", command.a, command.b);
-            let path = self.connect_once(command.a, &vec![&command.b], &tx);
-            if path.is_empty() {
-                continue;
-            }
-            for (a, b) in path.iter() {
-                self.road_level[a.0][a.1] = 1;
-                self.road_level[b.0][b.1] = 1;
-            }
-        }
-    }
-
-    pub fn _connect_randoms_forever(&mut self, tx: Sender<DijkstraUpdate>) {
+        let mut jobs = JobQueue {
+            command_rx,
+            tx: tx.clone(),
+            pending: VecDeque::new(),
+            grow: None,
+        };
         let mut rng = rand::rng();
-        let mut houses = HashSet::new();
-        let mut lakeside_points = Vec::new();
-        for r in 5..(self.height - 5) {
-            for c in 5..(self.width - 5) {
-                if self.is_water[r][c] {
+        while let Some(work) = jobs.next() {
+            let job = match work {
+                Work::Route(job) => job,
+                Work::Plan(job) => {
+                    let _ = tx.send(DijkstraUpdate::Job(job.id, JobStatus::Running));
+                    let status = match autoplan(self, &job, &tx, &mut || jobs.interrupted(job.id)) {
+                        None => JobStatus::Cancelled,
+                        Some(0) => JobStatus::NotFound,
+                        Some(_) => JobStatus::Done,
+                    };
+                    let _ = tx.send(DijkstraUpdate::Job(job.id, status));
                     continue;
                 }
-                'outer: for dr in -5..=5 {
-                    for dc in -5..=5 {
-                        if r as isize + dr < 0
-                            || c as isize + dc < 0
-                            || r as isize + dr >= self.height as isize
-                            || c as isize + dc >= self.width as isize
-                        {
-                            continue;
-                        }
-                        let nr = r as isize + dr;
-                        let nc = c as isize + dc;
-                        if self.is_water[nr as usize][nc as usize] {
-                            lakeside_points.push((r, c));
-                            break 'outer;
-                        }
-                    }
+                Work::Grow(network) => {
+                    let _ = tx.send(DijkstraUpdate::Growth(grow(self, &network, &mut rng)));
+                    continue;
                 }
-            }
+                Work::Terrain(Reshape { id, ground }) => {
+                    let (height_map, is_water) = extract_water(&ground, true);
+                    self.height_map = height_map.clone();
+                    self.is_water = is_water.clone();
+                    let _ = tx.send(DijkstraUpdate::Water(Water {
+                        id,
+                        height_map,
+                        is_water,
+                    }));
+                    continue;
+                }
+            };
+            println!(
+                "Job #{}: connect {:?} to {:?} by {:?}",
+                job.id, job.a, job.b, job.mode
+            );
+            let _ = tx.send(DijkstraUpdate::Job(job.id, JobStatus::Running));
+            let options = self.plan_alternatives(
+                &job.network,
+                job.mode,
+                job.a,
+                job.b,
+                Some(&tx),
+                &mut || jobs.interrupted(job.id),
+            );
+            let status = match options {
+                None => JobStatus::Cancelled,
+                Some(options) if options.is_empty() => JobStatus::NotFound,
+                Some(options) => {
+                    let _ = tx.send(DijkstraUpdate::Alternatives(Alternatives {
+                        id: job.id,
+                        options,
+                    }));
+                    JobStatus::Done
+                }
+            };
+            let _ = tx.send(DijkstraUpdate::Job(job.id, status));
+        }
+    }
+
+    // Finds the cheapest route from a to b on the given network without
+    // changing anything. Use `MapState::commit` to actually build it.
+    pub fn plan_route(
+        &self,
+        network: &Network,
+        mode: Mode,
+        a: (usize, usize),
+        b: (usize, usize),
+        tx: Option<&Sender<DijkstraUpdate>>,
+    ) -> Option<RoutePlan> {
+        if self.is_water[a.0][a.1] || self.is_water[b.0][b.1] {
+            return None;
         }
-        lakeside_points.shuffle(&mut rng);
-        const PATHS_AT_ONCE: usize = 1;
-        for _ in 0..2 * PATHS_AT_ONCE {
-            houses.insert(lakeside_points.pop().unwrap());
+        let targets = HashSet::from([b]);
+        let path = self.search(
+            network,
+            mode,
+            a,
+            &targets,
+            &HashSet::new(),
+            1.0,
+            tx,
+            &mut || false,
+        )?;
+        if path.is_empty() {
+            return None;
         }
-        loop {
-            let path = self.connect_once(
-                *houses.iter().choose(&mut rng).unwrap(),
-                &houses.iter().choose_multiple(&mut rng, PATHS_AT_ONCE),
-                &tx,
-            );
-            // let path = self.connect_once(
-            //     loop {
-            //         let r = rng.random_range(0..self.height);
-            //         let c = rng.random_range(0..self.width);
-            //         if self.is_water[r][c] {
-            //             continue;
-            //         }
-            //         break (r, c);
-            //     },
-            //     &vec![&loop {
-            //         let r = rng.random_range(0..self.height);
-            //         let c = rng.random_range(0..self.width);
-            //         if self.is_water[r][c] {
-            //             continue;
-            //         }
-            //         break (r, c);
-            //     }],
-            //     &tx,
-            // );
+        Some(RoutePlan {
+            mode,
+            a,
+            b,
+            breakdown: self.breakdown(network, mode, &path),
+            path,
+        })
+    }
+
+    // Finds up to ALTERNATIVES routes from a to b, each sharing at most
+    // MAX_ALTERNATIVE_OVERLAP of its cells with the ones found before it.
+    // Cells of every route found so far are made more expensive, and the
+    // penalty grows whenever the search comes back with a near-duplicate.
+    // Returns None if the search was interrupted.
+    pub fn plan_alternatives(
+        &self,
+        network: &Network,
+        mode: Mode,
+        a: (usize, usize),
+        b: (usize, usize),
+        tx: Option<&Sender<DijkstraUpdate>>,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<Vec<RoutePlan>> {
+        let mut options = Vec::new();
+        if self.is_water[a.0][a.1] || self.is_water[b.0][b.1] {
+            return Some(options);
+        }
+        let targets = HashSet::from([b]);
+        let mut option_cells: Vec<HashSet<(usize, usize)>> = Vec::new();
+        let mut penalized = HashSet::new();
+        let mut penalty = ALTERNATIVE_PENALTY;
+        for _ in 0..2 * ALTERNATIVES {
+            if options.len() == ALTERNATIVES {
+                break;
+            }
+            let path = self.search(
+                network,
+                mode,
+                a,
+                &targets,
+                &penalized,
+                penalty,
+                tx,
+                interrupted,
+            )?;
             if path.is_empty() {
-                continue;
+                break;
             }
-            for _ in 0..PATHS_AT_ONCE {
-                let midpoint = path.choose(&mut rng).unwrap();
-                let mut maybe_new_houses = Vec::new();
-                for dr in -1..=1 {
-                    for dc in -1..=1 {
-                        if dr == 0 && dc == 0 {
-                            continue;
-                        }
-                        let inr = midpoint.1.0 as isize + dr;
-                        let inc = midpoint.1.1 as isize + dc;
-                        if inr < 0
-                            || inc < 0
-                            || inr >= self.height as isize
-                            || inc >= self.width as isize
-                        {
-                            continue;
-                        }
-                        let nr = inr as usize;
-                        let nc = inc as usize;
-                        if !self.is_water[nr][nc]
-                            && self.house_level[nr][nc] == 0
-                            && self.road_level[nr][nc] == 0
-                        {
-                            maybe_new_houses.push((nr, nc));
-                        }
-                    }
+            let cells = path
+                .iter()
+                .flat_map(|&(to, from)| [to, from])
+                .collect::<HashSet<_>>();
+            let distinct = option_cells.iter().all(|other| {
+                cells.intersection(other).count() as f32 / cells.len() as f32
+                    <= MAX_ALTERNATIVE_OVERLAP
+            });
+            penalized.extend(cells.iter().cloned());
+            if distinct {
+                options.push(RoutePlan {
+                    mode,
+                    a,
+                    b,
+                    breakdown: self.breakdown(network, mode, &path),
+                    path,
+                });
+                option_cells.push(cells);
+            } else {
+                penalty *= 2.0;
+            }
+        }
+        Some(options)
+    }
+
+    // Segments are stored from the destination back towards the start, so
+    // climbing is measured from the second cell of a segment to the first.
+    pub fn breakdown(&self, network: &Network, mode: Mode, path: &[Segment]) -> RouteBreakdown {
+        let mut breakdown = RouteBreakdown::default();
+        for &(to, from) in path.iter() {
+            let dist = (((to.0 as isize - from.0 as isize).pow(2)
+                + (to.1 as isize - from.1 as isize).pow(2)) as f32)
+                .sqrt();
+            let height_diff = self.height_map[to.0][to.1] - self.height_map[from.0][from.1];
+            let (kind, base_cost, climb_cost) = self.step_cost(network, mode, from, to);
+            let grade = self.scale.grade(height_diff.abs(), dist);
+            let height_diff = self.scale.metres(height_diff);
+            let dist = self.scale.kilometres(dist);
+            breakdown.length += dist;
+            match kind {
+                StepKind::ExistingTrack => {
+                    breakdown.existing_length += dist;
+                    breakdown.existing_cost += base_cost;
                 }
-                if maybe_new_houses.is_empty() {
-                    continue;
+                StepKind::NewTrack => {
+                    breakdown.new_length += dist;
+                    breakdown.new_track_cost += base_cost;
+                }
+                StepKind::Bridge => {
+                    breakdown.bridge_length += dist;
+                    breakdown.bridge_cost += base_cost;
                 }
-                let new_house = maybe_new_houses.choose(&mut rng).unwrap().clone();
-                houses.insert(new_house);
-                self.house_level[new_house.0][new_house.1] = 1;
             }
-            for _ in 0..PATHS_AT_ONCE {
-                let random_house = houses.iter().choose(&mut rng).unwrap().clone();
-                self.house_level[random_house.0][random_house.1] = 0;
-                houses.remove(&random_house);
-                let moved_house = lakeside_points.pop().unwrap();
-                self.house_level[moved_house.0][moved_house.1] = 1;
-                houses.insert(moved_house);
+            breakdown.climb_cost += climb_cost;
+            if height_diff > 0.0 {
+                breakdown.climb += height_diff;
+            } else {
+                breakdown.descent -= height_diff;
             }
+            breakdown.max_grade = breakdown.max_grade.max(grade);
         }
+        breakdown
     }
 
-    fn connect_once(
-        &mut self,
-        a: (usize, usize),
-        b: &Vec<&(usize, usize)>,
-        tx: &Sender<DijkstraUpdate>,
-    ) -> Vec<((usize, usize), (usize, usize))> {
-        if self.is_water[a.0][a.1] {
-            return Vec::new();
+    pub fn costs(&self, mode: Mode) -> &CostModel {
+        match mode {
+            Mode::Rail => &self.rail_costs,
+            Mode::Road => &self.road_costs,
         }
-        let good_targets = b
+    }
+
+    // What raising the built cells among `cells` by one tier costs.
+    pub fn upgrade_cost(&self, network: &Network, mode: Mode, cells: &[(usize, usize)]) -> f32 {
+        let upgradable = cells
             .iter()
-            .filter(|&&b| !self.is_water[b.0][b.1])
-            .map(|&&b| b)
-            .collect::<HashSet<_>>();
+            .filter(|&&(row, col)| {
+                network.level(mode)[row][col] != 0 && network.tier(mode)[row][col].next().is_some()
+            })
+            .count();
+        self.costs(mode).upgrade * self.scale.kilometres(upgradable as f32)
+    }
+
+    // Cost of a single search step split into the part that depends on what
+    // is built on the target cell and the part paid for the height change,
+    // both higher if the target cell is in a penalty zone.
+    pub fn step_cost(
+        &self,
+        network: &Network,
+        mode: Mode,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> (StepKind, f32, f32) {
+        let squared_dist =
+            (to.0 as f32 - from.0 as f32).powi(2) + (to.1 as f32 - from.1 as f32).powi(2);
+        // Long steps cost less than their length, favouring straight track.
+        let factor = squared_dist.powf(0.4);
+        let kilometres = self.scale.kilometres(factor);
+        let costs = self.costs(mode);
+        let kind = if network.level(mode)[to.0][to.1] != 0 {
+            StepKind::ExistingTrack
+        } else if self.is_water[to.0][to.1] {
+            StepKind::Bridge
+        } else {
+            StepKind::NewTrack
+        };
+        let base_cost = match kind {
+            StepKind::ExistingTrack => {
+                costs.step_on_road * costs.tier_step[network.tier(mode)[to.0][to.1] as usize]
+            }
+            StepKind::NewTrack => costs.build_road,
+            StepKind::Bridge => costs.build_bridge,
+        } * kilometres;
+        let climb = self
+            .scale
+            .metres((self.height_map[to.0][to.1] - self.height_map[from.0][from.1]).abs());
+        let steepness = climb * costs.steepness_weight * factor;
+        let zone = match network.zones[to.0][to.1] {
+            Zone::Penalty => ZONE_PENALTY,
+            _ => 1.0,
+        };
+        (kind, zone * base_cost, zone * steepness * steepness)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search(
+        &self,
+        network: &Network,
+        mode: Mode,
+        a: (usize, usize),
+        good_targets: &HashSet<(usize, usize)>,
+        penalized: &HashSet<(usize, usize)>,
+        penalty: f32,
+        tx: Option<&Sender<DijkstraUpdate>>,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<Vec<Segment>> {
         let mut targets_connected = HashSet::new();
-        println!("Connecting {:?} to {:?}", a, b);
-        let cost_of_step_on_road = OrderedFloat(1.0);
-        let cost_of_build_road = OrderedFloat(3.0);
-        let cost_of_build_bridge = OrderedFloat(10.0);
-        let cost_of_climb_multiplier = OrderedFloat(3000.0);
 
         let mut dist = HashMap::new();
         let mut come_from = HashMap::new();
         let mut visited = HashSet::new();
+        let mut newly_visited = Vec::new();
         let mut queue = PriorityQueue::new();
 
         dist.insert(a, OrderedFloat(0.0));
@@ -184,6 +604,17 @@ impl Dijkstra {
                 continue;
             }
             visited.insert(current);
+            newly_visited.push(current);
+            if visited.len() % INTERRUPT_CHECK_INTERVAL == 0 && interrupted() {
+                println!("Search from {:?} interrupted", a);
+                return None;
+            }
+            if let Some(tx) = tx.filter(|_| visited.len() % SNAPSHOT_INTERVAL == 0) {
+                let _ = tx.send(DijkstraUpdate::Search(SearchSnapshot {
+                    visited: std::mem::take(&mut newly_visited),
+                    frontier: queue.iter().map(|(&cell, _)| cell).collect(),
+                }));
+            }
             if good_targets.contains(&current) {
                 targets_connected.insert(current);
                 if targets_connected.len() == good_targets.len() {
@@ -199,7 +630,6 @@ impl Dijkstra {
                     if dr * dr + dc * dc > 16 {
                         continue;
                     }
-                    let factor = ((dr as f32) * (dr as f32) + (dc as f32) * (dc as f32)).pow(0.4);
                     let inr = current.0 as isize + dr;
                     let inc = current.1 as isize + dc;
                     if inr < 0
@@ -211,23 +641,24 @@ impl Dijkstra {
                     }
                     let nr = inr as usize;
                     let nc = inc as usize;
-                    if self.house_level[nr][nc] != 0 && !good_targets.contains(&(nr, nc)) {
-                        continue;
-                    }
-                    let mut steepness_cost = OrderedFloat(
-                        (self.height_map[nr][nc] - self.height_map[current.0][current.1]).abs(),
-                    ) * cost_of_climb_multiplier
-                        * factor;
-                    steepness_cost = steepness_cost * steepness_cost;
-                    if self.road_level[nr][nc] != 0 {
-                        neighbors.push((cost_of_step_on_road * factor + steepness_cost, (nr, nc)));
+                    // Longer steps must not jump over anything either.
+                    let blocked = |cell| network.is_blocked(cell) && !good_targets.contains(&cell);
+                    if blocked((nr, nc))
+                        || (dr * dr + dc * dc > 2
+                            && segment_cells(current, (nr, nc))
+                                .into_iter()
+                                .skip(1)
+                                .any(blocked))
+                    {
                         continue;
                     }
-                    if self.is_water[nr][nc] {
-                        neighbors.push((cost_of_build_bridge * factor + steepness_cost, (nr, nc)));
-                        continue;
+                    let (_, base_cost, climb_cost) =
+                        self.step_cost(network, mode, current, (nr, nc));
+                    let mut cost = OrderedFloat(base_cost + climb_cost);
+                    if penalized.contains(&(nr, nc)) {
+                        cost *= penalty;
                     }
-                    neighbors.push((cost_of_build_road * factor + steepness_cost, (nr, nc)));
+                    neighbors.push((cost, (nr, nc)));
                 }
             }
             for (cost, neighbor) in neighbors {
@@ -239,29 +670,20 @@ impl Dijkstra {
                 }
             }
         }
+        if let Some(tx) = tx {
+            let _ = tx.send(DijkstraUpdate::Search(SearchSnapshot {
+                visited: newly_visited,
+                frontier: queue.iter().map(|(&cell, _)| cell).collect(),
+            }));
+        }
         let mut path = Vec::new();
         for &current in targets_connected.iter() {
             let mut curr = current;
-            while let Some((r, c)) = come_from.get(&curr) {
-                if *r == a.0 && *c == a.1 {
-                    break;
-                }
-                path.push((curr, (*r, *c)));
-                curr = (*r, *c);
-                self.road_level[*r][*c] = 1;
+            while let Some(&previous) = come_from.get(&curr) {
+                path.push((curr, previous));
+                curr = previous;
             }
-            self.house_level[current.0][current.1] = 1;
-            self.road_level[current.0][current.1] = 0;
         }
-        self.house_level[a.0][a.1] = 1;
-        self.road_level[a.0][a.1] = 0;
-        println!("Path length: {}", path.len());
-        let mut houses = vec![a];
-        houses.extend(good_targets.iter().cloned());
-        let _ = tx.send(DijkstraUpdate {
-            path: path.clone(),
-            houses,
-        });
-        return path;
+        Some(path)
     }
 }
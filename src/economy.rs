@@ -0,0 +1,166 @@
+use crate::demand::Cargo;
+use crate::dijkstra::RouteBreakdown;
+use crate::network::Mode;
+use bevy::prelude::*;
+
+pub const STARTING_FUNDS: f32 = 5_000_000.0;
+// Money per unit of router cost. Reusing existing track is free.
+pub const PRICE_PER_COST: f32 = 1_000.0;
+pub const TRAIN_PRICE: f32 = 200_000.0;
+pub const ROAD_VEHICLE_PRICE: f32 = 40_000.0;
+// Money per second for each train.
+pub const TRAIN_RUNNING_COST: f32 = 100.0;
+// Money per passenger or tonne per cell between the stations.
+pub const PASSENGER_FARE: f32 = 5.0;
+pub const FREIGHT_RATE: f32 = 3.0;
+
+// What building a route costs.
+pub fn route_price(breakdown: &RouteBreakdown) -> f32 {
+    PRICE_PER_COST * (breakdown.total_cost() - breakdown.existing_cost)
+}
+
+pub fn upgrade_price(cost: f32) -> f32 {
+    PRICE_PER_COST * cost
+}
+
+pub fn vehicle_price(mode: Mode) -> f32 {
+    match mode {
+        Mode::Rail => TRAIN_PRICE,
+        Mode::Road => ROAD_VEHICLE_PRICE,
+    }
+}
+
+// What carrying one passenger or tonne between two stations earns.
+pub fn fare(cargo: Cargo, from: (usize, usize), to: (usize, usize)) -> f32 {
+    let distance = ((from.0.abs_diff(to.0).pow(2) + from.1.abs_diff(to.1).pow(2)) as f32).sqrt();
+    match cargo {
+        Cargo::Passengers => PASSENGER_FARE * distance,
+        Cargo::Freight => FREIGHT_RATE * distance,
+    }
+}
+
+pub fn format_money(amount: f32) -> String {
+    let sign = if amount < 0.0 { "-" } else { "" };
+    let amount = amount.abs();
+    if amount >= 1_000_000.0 {
+        format!("{}${:.2}M", sign, amount / 1_000_000.0)
+    } else if amount >= 1_000.0 {
+        format!("{}${:.0}k", sign, amount / 1_000.0)
+    } else {
+        format!("{}${:.0}", sign, amount)
+    }
+}
+
+// The treasury and where its money went.
+#[derive(Resource)]
+pub struct Economy {
+    pub treasury: f32,
+    pub construction: f32,
+    pub trains: f32,
+    pub running_costs: f32,
+    pub revenue: f32,
+}
+
+impl Default for Economy {
+    fn default() -> Self {
+        Economy {
+            treasury: STARTING_FUNDS,
+            construction: 0.0,
+            trains: 0.0,
+            running_costs: 0.0,
+            revenue: 0.0,
+        }
+    }
+}
+
+impl Economy {
+    // Pays for construction and trains, or explains why it cannot.
+    pub fn buy(&mut self, construction: f32, trains: f32) -> Result<(), String> {
+        let price = construction + trains;
+        if price > self.treasury {
+            return Err(format!(
+                "costs {}, only {} in the treasury",
+                format_money(price),
+                format_money(self.treasury)
+            ));
+        }
+        self.treasury -= price;
+        self.construction += construction;
+        self.trains += trains;
+        Ok(())
+    }
+
+    // Gives back what was paid for an undone edit, or pays again on redo.
+    pub fn refund(&mut self, construction: f32, trains: f32) {
+        self.treasury += construction + trains;
+        self.construction -= construction;
+        self.trains -= trains;
+    }
+
+    pub fn run(&mut self, trains: usize, seconds: f32) {
+        let cost = TRAIN_RUNNING_COST * trains as f32 * seconds;
+        self.treasury -= cost;
+        self.running_costs += cost;
+    }
+
+    pub fn earn(&mut self, amount: f32) {
+        self.treasury += amount;
+        self.revenue += amount;
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Treasury {} (track {}, trains {}, running {}, revenue {})",
+            format_money(self.treasury),
+            format_money(self.construction),
+            format_money(self.trains),
+            format_money(self.running_costs),
+            format_money(self.revenue)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buy_pays_for_construction_and_trains() {
+        let mut economy = Economy::default();
+        assert!(economy.buy(1_000_000.0, TRAIN_PRICE).is_ok());
+        assert_eq!(economy.treasury, STARTING_FUNDS - 1_000_000.0 - TRAIN_PRICE);
+        assert_eq!(economy.construction, 1_000_000.0);
+        assert_eq!(economy.trains, TRAIN_PRICE);
+    }
+
+    #[test]
+    fn buy_refuses_what_the_treasury_cannot_pay() {
+        let mut economy = Economy::default();
+        let error = economy.buy(STARTING_FUNDS, TRAIN_PRICE).unwrap_err();
+        assert_eq!(error, "costs $5.20M, only $5.00M in the treasury");
+        assert_eq!(economy.treasury, STARTING_FUNDS);
+        assert_eq!(economy.construction, 0.0);
+        assert_eq!(economy.trains, 0.0);
+    }
+
+    #[test]
+    fn refund_undoes_a_purchase() {
+        let mut economy = Economy::default();
+        economy.buy(250_000.0, ROAD_VEHICLE_PRICE).unwrap();
+        economy.refund(250_000.0, ROAD_VEHICLE_PRICE);
+        assert_eq!(economy.treasury, STARTING_FUNDS);
+        assert_eq!(economy.construction, 0.0);
+        assert_eq!(economy.trains, 0.0);
+    }
+
+    #[test]
+    fn existing_track_is_free() {
+        let breakdown = RouteBreakdown {
+            existing_cost: 2.0,
+            new_track_cost: 3.0,
+            climb_cost: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(route_price(&breakdown), 4.0 * PRICE_PER_COST);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::dijkstra::Dijkstra;
+use crate::network::RoutePlan;
+use crate::scale::Scale;
+use ordered_float::OrderedFloat;
+use priority_queue::PriorityQueue;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+pub type EdgeId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Station,
+    Junction,
+    Endpoint,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Structure {
+    Track,
+    Bridge,
+}
+
+// A stretch of track only one train may be on at a time. Stations hold any
+// number of trains and so are not blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Block {
+    Edge(EdgeId),
+    Node((usize, usize)),
+}
+
+// Why a train could not be routed between two cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoutingError {
+    NotAStation((usize, usize)),
+    SameStation,
+    NotConnected((usize, usize), (usize, usize)),
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoutingError::NotAStation(cell) => write!(f, "{:?} is not a station", cell),
+            RoutingError::SameStation => write!(f, "Pick two different stations"),
+            RoutingError::NotConnected(a, b) => {
+                write!(f, "Stations {:?} and {:?} are not connected by track", a, b)
+            }
+        }
+    }
+}
+
+// A stretch of track between two nodes, with every cell from `a` to `b`.
+#[derive(Clone, Debug)]
+pub struct Edge {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+    pub cells: Vec<(usize, usize)>,
+    // Lengths in cells, climb and descent in metres and the grade in percent.
+    pub length: f32,
+    pub climb: f32,
+    pub descent: f32,
+    pub max_grade: f32,
+    pub bridge_length: f32,
+}
+
+impl Edge {
+    pub fn structure(&self) -> Structure {
+        if self.bridge_length * 2.0 > self.length {
+            Structure::Bridge
+        } else {
+            Structure::Track
+        }
+    }
+
+    pub fn other_end(&self, node: (usize, usize)) -> (usize, usize) {
+        if node == self.a { self.b } else { self.a }
+    }
+}
+
+// The track network as a graph. Nodes are identified by their cell. Cells
+// are linked when a built route steps from one to the other, and every cell
+// that is a station or not linked to exactly two others is a node.
+#[derive(Clone, Default)]
+pub struct NetworkGraph {
+    pub nodes: HashMap<(usize, usize), NodeKind>,
+    pub edges: BTreeMap<EdgeId, Edge>,
+    // How many routes use each link, in both directions.
+    links: HashMap<(usize, usize), HashMap<(usize, usize), u32>>,
+    // Edges through or ending at each cell.
+    cell_edges: HashMap<(usize, usize), Vec<EdgeId>>,
+    next_edge_id: EdgeId,
+}
+
+impl NetworkGraph {
+    pub fn add_route(&mut self, plan: &RoutePlan) {
+        let cells = plan.cells();
+        for pair in cells.windows(2) {
+            *self
+                .links
+                .entry(pair[0])
+                .or_default()
+                .entry(pair[1])
+                .or_default() += 1;
+            *self
+                .links
+                .entry(pair[1])
+                .or_default()
+                .entry(pair[0])
+                .or_default() += 1;
+        }
+    }
+
+    pub fn remove_route(&mut self, plan: &RoutePlan) {
+        let cells = plan.cells();
+        for pair in cells.windows(2) {
+            self.unlink(pair[0], pair[1]);
+            self.unlink(pair[1], pair[0]);
+        }
+    }
+
+    fn unlink(&mut self, from: (usize, usize), to: (usize, usize)) {
+        let Some(neighbors) = self.links.get_mut(&from) else {
+            return;
+        };
+        if let Some(count) = neighbors.get_mut(&to) {
+            *count -= 1;
+            if *count == 0 {
+                neighbors.remove(&to);
+            }
+        }
+        if neighbors.is_empty() {
+            self.links.remove(&from);
+        }
+    }
+
+    pub fn edges_at(&self, node: (usize, usize)) -> impl Iterator<Item = (EdgeId, &Edge)> {
+        self.cell_edges
+            .get(&node)
+            .into_iter()
+            .flatten()
+            .map(|id| (*id, &self.edges[id]))
+            .filter(move |(_, edge)| edge.a == node || edge.b == node)
+    }
+
+    pub fn block_at(&self, cell: (usize, usize)) -> Option<Block> {
+        match self.nodes.get(&cell) {
+            Some(NodeKind::Station) => None,
+            Some(_) => Some(Block::Node(cell)),
+            None => self
+                .cell_edges
+                .get(&cell)
+                .and_then(|ids| ids.first())
+                .map(|&id| Block::Edge(id)),
+        }
+    }
+
+    // Other edges joining the same two nodes as `id`, such as passing loops.
+    pub fn parallel_edges(&self, id: EdgeId) -> impl Iterator<Item = (EdgeId, &Edge)> {
+        let edge = &self.edges[&id];
+        self.edges_at(edge.a).filter(move |&(other, other_edge)| {
+            other != id
+                && ((other_edge.a == edge.a && other_edge.b == edge.b)
+                    || (other_edge.a == edge.b && other_edge.b == edge.a))
+        })
+    }
+
+    // The shortest way along built track between two stations, as every cell
+    // from `from` to `to`.
+    pub fn route(
+        &self,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Result<Vec<(usize, usize)>, RoutingError> {
+        for station in [from, to] {
+            if self.nodes.get(&station) != Some(&NodeKind::Station) {
+                return Err(RoutingError::NotAStation(station));
+            }
+        }
+        if from == to {
+            return Err(RoutingError::SameStation);
+        }
+        let mut dist = HashMap::new();
+        let mut come_from = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut queue = PriorityQueue::new();
+        dist.insert(from, OrderedFloat(0.0));
+        queue.push(from, OrderedFloat(0.0));
+        while let Some((current, current_dist)) = queue.pop() {
+            if !visited.insert(current) {
+                continue;
+            }
+            if current == to {
+                break;
+            }
+            for (id, edge) in self.edges_at(current) {
+                let neighbor = edge.other_end(current);
+                let new_dist = current_dist - edge.length;
+                if new_dist > *dist.get(&neighbor).unwrap_or(&OrderedFloat(f32::MIN)) {
+                    dist.insert(neighbor, new_dist);
+                    come_from.insert(neighbor, (current, id));
+                    queue.push(neighbor, new_dist);
+                }
+            }
+        }
+        if !visited.contains(&to) {
+            return Err(RoutingError::NotConnected(from, to));
+        }
+        let mut cells = vec![to];
+        let mut current = to;
+        while let Some(&(previous, id)) = come_from.get(&current) {
+            let edge = &self.edges[&id];
+            if edge.b == current {
+                cells.extend(edge.cells.iter().rev().skip(1));
+            } else {
+                cells.extend(edge.cells.iter().skip(1));
+            }
+            current = previous;
+        }
+        cells.reverse();
+        Ok(cells)
+    }
+
+    pub fn summary(&self, scale: &Scale) -> String {
+        let count = |kind| self.nodes.values().filter(|&&k| k == kind).count();
+        let track = self.edges.values().map(|edge| edge.length).sum::<f32>();
+        let bridges = self
+            .edges
+            .values()
+            .filter(|edge| edge.structure() == Structure::Bridge)
+            .count();
+        let steepest = self
+            .edges
+            .values()
+            .map(|edge| edge.max_grade)
+            .fold(0.0, f32::max);
+        format!(
+            "{} stations, {} junctions, {} dead ends; {} edges, {:.1} km of track, {} bridges, steepest grade {:.1}%",
+            count(NodeKind::Station),
+            count(NodeKind::Junction),
+            count(NodeKind::Endpoint),
+            self.edges.len(),
+            scale.kilometres(track),
+            bridges,
+            steepest
+        )
+    }
+
+    fn neighbors(&self, cell: (usize, usize)) -> Vec<(usize, usize)> {
+        let mut neighbors = self
+            .links
+            .get(&cell)
+            .map(|neighbors| neighbors.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        neighbors.sort();
+        neighbors
+    }
+
+    fn node_kind(
+        &self,
+        cell: (usize, usize),
+        stations: &HashSet<(usize, usize)>,
+    ) -> Option<NodeKind> {
+        if stations.contains(&cell) {
+            return Some(NodeKind::Station);
+        }
+        match self.neighbors(cell).len() {
+            0 | 2 => None,
+            1 => Some(NodeKind::Endpoint),
+            _ => Some(NodeKind::Junction),
+        }
+    }
+
+    // Re-derives nodes and edges around cells whose links or station status
+    // changed. Edges touching those cells are dropped and traced again.
+    pub fn refresh(
+        &mut self,
+        changed: impl IntoIterator<Item = (usize, usize)>,
+        stations: &HashSet<(usize, usize)>,
+        terrain: &Dijkstra,
+    ) {
+        let mut dirty = changed.into_iter().collect::<HashSet<_>>();
+        let stale = dirty
+            .iter()
+            .filter_map(|cell| self.cell_edges.get(cell))
+            .flatten()
+            .cloned()
+            .collect::<HashSet<_>>();
+        for id in stale {
+            let edge = self.edges.remove(&id).unwrap();
+            for cell in edge.cells.iter() {
+                if let Some(ids) = self.cell_edges.get_mut(cell) {
+                    ids.retain(|other| *other != id);
+                    if ids.is_empty() {
+                        self.cell_edges.remove(cell);
+                    }
+                }
+                dirty.insert(*cell);
+            }
+        }
+        for &cell in dirty.iter() {
+            match self.node_kind(cell, stations) {
+                Some(kind) => {
+                    self.nodes.insert(cell, kind);
+                }
+                None => {
+                    self.nodes.remove(&cell);
+                }
+            }
+        }
+        let mut starts = dirty
+            .iter()
+            .filter(|cell| self.nodes.contains_key(cell))
+            .cloned()
+            .collect::<Vec<_>>();
+        starts.sort();
+        for start in starts {
+            self.trace_from(start, terrain);
+        }
+        // Whatever is left untraced is a loop without any node on it.
+        let mut loose = dirty
+            .into_iter()
+            .filter(|cell| self.links.contains_key(cell) && !self.cell_edges.contains_key(cell))
+            .collect::<Vec<_>>();
+        loose.sort();
+        for cell in loose {
+            if self.cell_edges.contains_key(&cell) {
+                continue;
+            }
+            self.nodes.insert(cell, NodeKind::Junction);
+            self.trace_from(cell, terrain);
+        }
+    }
+
+    fn trace_from(&mut self, start: (usize, usize), terrain: &Dijkstra) {
+        for first in self.neighbors(start) {
+            let covered = self.cell_edges.get(&first).into_iter().flatten().any(|id| {
+                let cells = &self.edges[id].cells;
+                cells.windows(2).any(|pair| {
+                    (pair[0] == start && pair[1] == first) || (pair[0] == first && pair[1] == start)
+                })
+            });
+            if covered {
+                continue;
+            }
+            let mut cells = vec![start, first];
+            let mut previous = start;
+            let mut current = first;
+            while !self.nodes.contains_key(&current) {
+                let Some(next) = self
+                    .neighbors(current)
+                    .into_iter()
+                    .find(|&next| next != previous)
+                else {
+                    break;
+                };
+                cells.push(next);
+                previous = current;
+                current = next;
+            }
+            self.add_edge(cells, terrain);
+        }
+    }
+
+    fn add_edge(&mut self, cells: Vec<(usize, usize)>, terrain: &Dijkstra) {
+        let mut edge = Edge {
+            a: cells[0],
+            b: cells[cells.len() - 1],
+            cells: Vec::new(),
+            length: 0.0,
+            climb: 0.0,
+            descent: 0.0,
+            max_grade: 0.0,
+            bridge_length: 0.0,
+        };
+        for pair in cells.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let dist = if from.0 != to.0 && from.1 != to.1 {
+                std::f32::consts::SQRT_2
+            } else {
+                1.0
+            };
+            let height_diff = terrain.height_map[to.0][to.1] - terrain.height_map[from.0][from.1];
+            let grade = terrain.scale.grade(height_diff.abs(), dist);
+            let height_diff = terrain.scale.metres(height_diff);
+            edge.length += dist;
+            if height_diff > 0.0 {
+                edge.climb += height_diff;
+            } else {
+                edge.descent -= height_diff;
+            }
+            edge.max_grade = edge.max_grade.max(grade);
+            if terrain.is_water[to.0][to.1] {
+                edge.bridge_length += dist;
+            }
+        }
+        let id = self.next_edge_id;
+        self.next_edge_id += 1;
+        for cell in cells.iter() {
+            let ids = self.cell_edges.entry(*cell).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        edge.cells = cells;
+        self.edges.insert(id, edge);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Mode;
+
+    // A straight line from west to east and a branch from its middle down
+    // to the south.
+    fn junction() -> (NetworkGraph, RoutePlan, HashSet<(usize, usize)>, Dijkstra) {
+        let terrain = Dijkstra::flat(8, 8);
+        let stations = HashSet::from([(0, 0), (0, 6), (4, 3)]);
+        let (line, branch) = (
+            RoutePlan::straight(Mode::Rail, (0, 0), (0, 6)),
+            RoutePlan::straight(Mode::Rail, (0, 3), (4, 3)),
+        );
+        let mut graph = NetworkGraph::default();
+        graph.add_route(&line);
+        graph.add_route(&branch);
+        graph.refresh(
+            line.cells().into_iter().chain(branch.cells()),
+            &stations,
+            &terrain,
+        );
+        (graph, branch, stations, terrain)
+    }
+
+    #[test]
+    fn refresh_finds_stations_junctions_and_edges() {
+        let (graph, _, _, _) = junction();
+        assert_eq!(graph.nodes.get(&(0, 3)), Some(&NodeKind::Junction));
+        assert_eq!(graph.nodes.get(&(4, 3)), Some(&NodeKind::Station));
+        assert_eq!(graph.nodes.len(), 4);
+        assert_eq!(graph.edges.len(), 3);
+        let track = graph.edges.values().map(|edge| edge.length).sum::<f32>();
+        assert_eq!(track, 10.0);
+    }
+
+    #[test]
+    fn refresh_merges_edges_when_a_branch_goes() {
+        let (mut graph, branch, stations, terrain) = junction();
+        graph.remove_route(&branch);
+        graph.refresh(branch.cells(), &stations, &terrain);
+        assert_eq!(graph.nodes.get(&(0, 3)), None);
+        assert_eq!(graph.edges.len(), 1);
+        assert_eq!(graph.edges.values().next().unwrap().length, 6.0);
+    }
+
+    #[test]
+    fn route_follows_track_through_junctions() {
+        let (graph, _, _, _) = junction();
+        let cells = graph.route((0, 0), (4, 3)).unwrap();
+        assert_eq!(cells.first(), Some(&(0, 0)));
+        assert_eq!(cells.last(), Some(&(4, 3)));
+        assert_eq!(cells.len(), 8);
+        assert!(cells.contains(&(0, 3)));
+    }
+
+    #[test]
+    fn route_reports_why_it_failed() {
+        let (graph, _, _, _) = junction();
+        assert_eq!(
+            graph.route((0, 0), (0, 3)),
+            Err(RoutingError::NotAStation((0, 3)))
+        );
+        assert_eq!(graph.route((0, 0), (0, 0)), Err(RoutingError::SameStation));
+        let terrain = Dijkstra::flat(8, 8);
+        let stations = HashSet::from([(0, 0), (0, 6), (6, 0), (6, 6)]);
+        let mut graph = NetworkGraph::default();
+        for plan in [
+            RoutePlan::straight(Mode::Rail, (0, 0), (0, 6)),
+            RoutePlan::straight(Mode::Rail, (6, 0), (6, 6)),
+        ] {
+            graph.add_route(&plan);
+            graph.refresh(plan.cells(), &stations, &terrain);
+        }
+        assert_eq!(
+            graph.route((0, 0), (6, 6)),
+            Err(RoutingError::NotConnected((0, 0), (6, 6)))
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use crate::line::{Line, LineId};
+use crate::state::Edit;
+use crate::train::{Train, TrainId};
+use bevy::prelude::*;
+
+// How many edits can be undone.
+const MAX_HISTORY: usize = 100;
+
+// An edit of the network together with the trains it added and removed,
+// the lines it created, and what was paid for construction and trains.
+pub struct HistoryEntry {
+    pub edit: Edit,
+    pub spawned: Vec<(TrainId, Train)>,
+    pub despawned: Vec<(TrainId, Train)>,
+    pub lines: Vec<(LineId, Line)>,
+    pub construction: f32,
+    pub purchases: f32,
+}
+
+#[derive(Resource, Default)]
+pub struct History {
+    pub undo: Vec<HistoryEntry>,
+    pub redo: Vec<HistoryEntry>,
+    next_train: u64,
+}
+
+impl History {
+    pub fn record(&mut self, entry: HistoryEntry) {
+        self.redo.clear();
+        self.undo.push(entry);
+        if self.undo.len() > MAX_HISTORY {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn next_train(&mut self) -> TrainId {
+        self.next_train += 1;
+        TrainId(self.next_train)
+    }
+}
@@ -0,0 +1,269 @@
+use crate::demand::Cargo;
+use crate::graph::{NetworkGraph, RoutingError};
+use crate::network::Mode;
+use crate::train::Train;
+use bevy::prelude::*;
+use std::collections::BTreeMap;
+
+pub type LineId = u64;
+
+// Defaults for lines created from the UI.
+pub const DEFAULT_LINE_TRAINS: usize = 2;
+pub const DEFAULT_LINE_DWELL: f32 = 3.0;
+pub const DEFAULT_HEADWAY: f32 = 15.0;
+// How many departures a station board lists.
+pub const BOARD_SIZE: usize = 6;
+
+// When trains leave the first station of a line, in seconds.
+#[derive(Clone)]
+pub enum Schedule {
+    Headway(f32),
+    // Departure times within a period that repeats.
+    Timetable { period: f32, departures: Vec<f32> },
+}
+
+impl Schedule {
+    // A timetable of departures tapped out at the given times, repeating
+    // from the first tap once `now` is reached. Without taps, or if the
+    // period would be empty, trains leave at the default headway.
+    pub fn tapped(taps: &[f32], now: f32) -> Schedule {
+        match (taps.first(), taps.last()) {
+            (Some(&first), Some(&last)) if now > last => Schedule::Timetable {
+                period: now - first,
+                departures: taps.iter().map(|tap| tap - first).collect(),
+            },
+            _ => Schedule::Headway(DEFAULT_HEADWAY),
+        }
+    }
+
+    // The first departure slot after `time`.
+    pub fn after(&self, time: f32) -> f32 {
+        match self {
+            Schedule::Headway(headway) => time + headway,
+            Schedule::Timetable { period, departures } => {
+                let start = (time / period).floor() * period;
+                [start, start + period]
+                    .into_iter()
+                    .flat_map(|start| departures.iter().map(move |departure| start + departure))
+                    .filter(|&slot| slot > time)
+                    .fold(f32::MAX, f32::min)
+            }
+        }
+    }
+}
+
+// Trains, or buses on a road line, calling at stations in order, turning
+// back at the last one.
+#[derive(Clone)]
+pub struct Line {
+    pub mode: Mode,
+    pub stations: Vec<(usize, usize)>,
+    pub trains: usize,
+    pub dwell: f32,
+    pub schedule: Schedule,
+    pub next_departure: f32,
+}
+
+impl Line {
+    // A train for the line, waiting at its first station. It follows built
+    // track or road, from the graph of the line's mode, from each station to
+    // the next.
+    pub fn train(&self, id: LineId, graph: &NetworkGraph) -> Result<Train, RoutingError> {
+        let mut path = vec![self.stations[0]];
+        let mut stops = Vec::new();
+        for pair in self.stations.windows(2) {
+            let leg = graph.route(pair[0], pair[1])?;
+            stops.push(path.len() - 1);
+            path.extend(leg.into_iter().skip(1));
+        }
+        let mut train = match self.mode {
+            Mode::Rail => Train::new(path),
+            Mode::Road => Train::road(path, Cargo::Passengers),
+        };
+        // The first station is the start of the path, not a stop.
+        train.stops = stops.into_iter().skip(1).collect();
+        train.line = Some(id);
+        train.dwell_time = self.dwell;
+        train.held = true;
+        Ok(train)
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Lines {
+    pub lines: BTreeMap<LineId, Line>,
+    pub next_id: LineId,
+    // Stations clicked so far for a line being created, and the times
+    // departures were tapped out for its timetable.
+    pub draft: Option<Vec<(usize, usize)>>,
+    pub tapped: Vec<f32>,
+}
+
+// A train expected to leave a station: when, on which line, and where to.
+pub struct Departure {
+    pub seconds: f32,
+    pub line: LineId,
+    pub destination: (usize, usize),
+}
+
+impl Lines {
+    // The next departures from a station, soonest first, estimated from
+    // where each line train is now.
+    pub fn departures<'a>(
+        &self,
+        station: (usize, usize),
+        trains: impl Iterator<Item = &'a Train>,
+        now: f32,
+    ) -> Vec<Departure> {
+        let mut departures = Vec::new();
+        for train in trains {
+            let Some(id) = train.line else {
+                continue;
+            };
+            let Some(line) = self.lines.get(&id) else {
+                continue;
+            };
+            let wait = if train.held {
+                (line.next_departure - now).max(0.0)
+            } else {
+                train.dwell.max(0.0)
+            };
+            let last = train.path.len() - 1;
+            let calls = train
+                .path
+                .iter()
+                .enumerate()
+                .filter(|&(_, &cell)| cell == station)
+                .map(|(index, _)| index);
+            for index in calls {
+                let offset = train.offsets[index];
+                let ahead = if train.forward {
+                    offset >= train.distance
+                } else {
+                    offset <= train.distance
+                };
+                // Going the way it faces, or on the way back after turning.
+                let (distance, forward) = if ahead {
+                    ((offset - train.distance).abs(), train.forward)
+                } else if train.forward {
+                    (2.0 * train.length() - train.distance - offset, false)
+                } else {
+                    (train.distance + offset, true)
+                };
+                let destination = if forward { last } else { 0 };
+                if index == destination {
+                    continue;
+                }
+                // Stops passed on the way, and the end it turns round at. At
+                // the first station it waits for a slot, which is not known.
+                let between = |a: f32, b: f32| {
+                    let (low, high) = (a.min(b), a.max(b));
+                    train
+                        .stops
+                        .iter()
+                        .filter(|&&stop| low < train.offsets[stop] && train.offsets[stop] < high)
+                        .count()
+                };
+                let stops = if ahead {
+                    between(train.distance, offset)
+                } else if train.forward {
+                    between(train.distance, train.length()) + between(offset, train.length()) + 1
+                } else {
+                    between(0.0, train.distance) + between(0.0, offset)
+                };
+                // A train already standing here has its dwell in `wait`.
+                let dwells = if distance == 0.0 { stops } else { stops + 1 };
+                departures.push(Departure {
+                    seconds: wait + distance / train.max_speed + dwells as f32 * train.dwell_time,
+                    line: id,
+                    destination: train.path[destination],
+                });
+            }
+        }
+        departures.sort_by(|a, b| a.seconds.total_cmp(&b.seconds));
+        departures.truncate(BOARD_SIZE);
+        departures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headway_departs_a_fixed_time_later() {
+        assert_eq!(Schedule::Headway(15.0).after(4.0), 19.0);
+    }
+
+    #[test]
+    fn timetable_departs_at_the_next_slot() {
+        let schedule = Schedule::Timetable {
+            period: 60.0,
+            departures: vec![0.0, 15.0, 22.5],
+        };
+        assert_eq!(schedule.after(10.0), 15.0);
+        assert_eq!(schedule.after(15.0), 22.5);
+        // Past the last slot of the period it wraps round to the next one.
+        assert_eq!(schedule.after(150.0), 180.0);
+    }
+
+    #[test]
+    fn tapped_departures_make_a_timetable() {
+        match Schedule::tapped(&[100.0, 110.0, 130.0], 160.0) {
+            Schedule::Timetable { period, departures } => {
+                assert_eq!(period, 60.0);
+                assert_eq!(departures, vec![0.0, 10.0, 30.0]);
+            }
+            Schedule::Headway(_) => panic!("expected a timetable"),
+        }
+        assert!(matches!(Schedule::tapped(&[], 160.0), Schedule::Headway(_)));
+        assert!(matches!(
+            Schedule::tapped(&[160.0], 160.0),
+            Schedule::Headway(_)
+        ));
+    }
+
+    // A line from (0, 0) to (0, 10) calling at (0, 5), with one train on it.
+    fn line_train(distance: f32, forward: bool) -> (Lines, Train) {
+        let mut train = Train::new((0..=10).map(|col| (0, col)).collect());
+        train.stops = vec![5];
+        train.line = Some(0);
+        train.arrived = None;
+        train.distance = distance;
+        train.forward = forward;
+        let mut lines = Lines::default();
+        lines.lines.insert(
+            0,
+            Line {
+                mode: Mode::Rail,
+                stations: vec![(0, 0), (0, 5), (0, 10)],
+                trains: 1,
+                dwell: train.dwell_time,
+                schedule: Schedule::Headway(DEFAULT_HEADWAY),
+                next_departure: 0.0,
+            },
+        );
+        (lines, train)
+    }
+
+    #[test]
+    fn departures_count_stops_ahead() {
+        let (lines, train) = line_train(2.0, true);
+        let departures = lines.departures((0, 8), [&train].into_iter(), 0.0);
+        assert_eq!(departures.len(), 1);
+        assert_eq!(departures[0].destination, (0, 10));
+        let expected = 6.0 / train.max_speed + 2.0 * train.dwell_time;
+        assert!((departures[0].seconds - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn departures_after_turning_count_stops_both_ways() {
+        let (lines, train) = line_train(7.0, true);
+        let departures = lines.departures((0, 3), [&train].into_iter(), 0.0);
+        assert_eq!(departures.len(), 1);
+        assert_eq!(departures[0].destination, (0, 0));
+        // Turning at the far end, then back past the stop at (0, 5).
+        let expected = 10.0 / train.max_speed + 3.0 * train.dwell_time;
+        assert!((departures[0].seconds - expected).abs() < 1e-4);
+    }
+}
@@ -1,8 +1,23 @@
+mod demand;
 mod dijkstra;
+mod economy;
+mod graph;
+mod history;
+mod line;
+mod network;
+mod planner;
+mod scale;
+mod settlement;
+mod signals;
 mod state;
+mod suitability;
+mod terraform;
 mod terrain;
+mod traffic;
 mod train;
 mod ui;
+mod walkers;
+mod zones;
 fn main() {
     ui::init(1024, 768);
 }
@@ -0,0 +1,518 @@
+use crate::dijkstra::{RouteBreakdown, Segment, segment_cells};
+use crate::zones::Zone;
+use std::collections::{BTreeMap, HashSet};
+
+pub type RouteId = u64;
+
+// Rail and road are built, routed and drawn separately, each on its own
+// layer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Mode {
+    #[default]
+    Rail,
+    Road,
+}
+
+// How far built infrastructure has been upgraded: track, double track and
+// electrified railways, or dirt, paved and highway roads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Tier {
+    #[default]
+    Basic,
+    Improved,
+    Major,
+}
+
+pub const TIERS: usize = 3;
+
+impl Tier {
+    pub fn name(self, mode: Mode) -> &'static str {
+        match (mode, self) {
+            (Mode::Rail, Tier::Basic) => "track",
+            (Mode::Rail, Tier::Improved) => "double track",
+            (Mode::Rail, Tier::Major) => "electrified",
+            (Mode::Road, Tier::Basic) => "dirt road",
+            (Mode::Road, Tier::Improved) => "paved road",
+            (Mode::Road, Tier::Major) => "highway",
+        }
+    }
+
+    pub fn next(self) -> Option<Tier> {
+        match self {
+            Tier::Basic => Some(Tier::Improved),
+            Tier::Improved => Some(Tier::Major),
+            Tier::Major => None,
+        }
+    }
+
+    // How much faster than its top speed on basic infrastructure a vehicle
+    // may run.
+    pub fn speed(self) -> f32 {
+        match self {
+            Tier::Basic => 1.0,
+            Tier::Improved => 1.3,
+            Tier::Major => 1.6,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Network {
+    // How many built routes of each mode pass through each cell, and the
+    // tier they are built to.
+    pub rail_level: Vec<Vec<i32>>,
+    pub road_level: Vec<Vec<i32>>,
+    pub rail_tier: Vec<Vec<Tier>>,
+    pub road_tier: Vec<Vec<Tier>>,
+    pub house_level: Vec<Vec<i32>>,
+    pub stations: HashSet<(usize, usize)>,
+    // Centres of the towns settlement growth has founded.
+    pub towns: Vec<(usize, usize)>,
+    pub routes: BTreeMap<RouteId, RoutePlan>,
+    next_route_id: RouteId,
+    // Areas painted as off limits or costly to build through, and how many
+    // cells are off limits so routing can skip the check when none are.
+    pub zones: Vec<Vec<Zone>>,
+    forbidden: usize,
+}
+
+// A cell whose level changed from the first value to the second.
+pub type CellChange = ((usize, usize), i32, i32);
+pub type TierChange = ((usize, usize), Tier, Tier);
+
+// Everything a single edit changed, so that it can be undone and redone.
+#[derive(Clone, Default)]
+pub struct NetworkEdit {
+    pub levels: Vec<(Mode, CellChange)>,
+    pub tiers: Vec<(Mode, TierChange)>,
+    pub houses: Vec<CellChange>,
+    pub stations_added: Vec<(usize, usize)>,
+    pub stations_removed: Vec<(usize, usize)>,
+    pub routes_added: Vec<(RouteId, RoutePlan)>,
+    pub routes_removed: Vec<(RouteId, RoutePlan)>,
+}
+
+// A route found by the router that has not been built yet.
+#[derive(Clone)]
+pub struct RoutePlan {
+    pub mode: Mode,
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+    pub path: Vec<Segment>,
+    pub breakdown: RouteBreakdown,
+}
+
+impl RoutePlan {
+    // A plan for a single straight segment from a to b, for tests.
+    #[cfg(test)]
+    pub fn straight(mode: Mode, a: (usize, usize), b: (usize, usize)) -> Self {
+        RoutePlan {
+            mode,
+            a,
+            b,
+            path: vec![(b, a)],
+            breakdown: RouteBreakdown::default(),
+        }
+    }
+
+    // Every cell the route passes through, from b to a.
+    pub fn cells(&self) -> Vec<(usize, usize)> {
+        let mut cells = Vec::new();
+        for &(start, end) in self.path.iter() {
+            let segment = segment_cells(start, end);
+            let skip = if cells.is_empty() { 0 } else { 1 };
+            cells.extend(segment.into_iter().skip(skip));
+        }
+        cells
+    }
+}
+
+impl Network {
+    pub fn new(width: usize, height: usize) -> Self {
+        Network {
+            rail_level: vec![vec![0; width]; height],
+            road_level: vec![vec![0; width]; height],
+            rail_tier: vec![vec![Tier::Basic; width]; height],
+            road_tier: vec![vec![Tier::Basic; width]; height],
+            house_level: vec![vec![0; width]; height],
+            stations: HashSet::new(),
+            towns: Vec::new(),
+            routes: BTreeMap::new(),
+            next_route_id: 0,
+            zones: vec![vec![Zone::Free; width]; height],
+            forbidden: 0,
+        }
+    }
+
+    pub fn load_zones(&mut self, zones: Vec<Vec<Zone>>) {
+        self.forbidden = zones
+            .iter()
+            .flatten()
+            .filter(|&&zone| zone == Zone::Forbidden)
+            .count();
+        self.zones = zones;
+    }
+
+    // Returns whether the zone of the cell changed.
+    pub fn set_zone(&mut self, (row, col): (usize, usize), zone: Zone) -> bool {
+        let before = self.zones[row][col];
+        if before == zone {
+            return false;
+        }
+        if before == Zone::Forbidden {
+            self.forbidden -= 1;
+        }
+        if zone == Zone::Forbidden {
+            self.forbidden += 1;
+        }
+        self.zones[row][col] = zone;
+        true
+    }
+
+    pub fn is_forbidden(&self, (row, col): (usize, usize)) -> bool {
+        self.forbidden > 0 && self.zones[row][col] == Zone::Forbidden
+    }
+
+    // Whether routes may not pass through a cell other than to end there:
+    // houses and stations stand on it, or it is off limits.
+    pub fn is_blocked(&self, (row, col): (usize, usize)) -> bool {
+        self.house_level[row][col] != 0 || self.is_forbidden((row, col))
+    }
+
+    // Whether a plan, possibly found on an older snapshot, still only runs
+    // through cells it may.
+    pub fn admits(&self, plan: &RoutePlan) -> bool {
+        plan.cells()
+            .into_iter()
+            .all(|cell| cell == plan.a || cell == plan.b || !self.is_blocked(cell))
+    }
+
+    pub fn commit(&mut self, plan: &RoutePlan) -> NetworkEdit {
+        let mut edit = self.lay(plan);
+        for station in [plan.a, plan.b] {
+            self.add_station(station, &mut edit);
+        }
+        edit
+    }
+
+    // Builds a route without stations at its ends.
+    pub fn lay(&mut self, plan: &RoutePlan) -> NetworkEdit {
+        let mut edit = NetworkEdit::default();
+        for (row, col) in plan.cells() {
+            let level = self.level(plan.mode)[row][col] + 1;
+            self.set_level(plan.mode, (row, col), level, &mut edit);
+        }
+        let id = self.next_route_id;
+        self.next_route_id += 1;
+        self.routes.insert(id, plan.clone());
+        edit.routes_added.push((id, plan.clone()));
+        edit
+    }
+
+    // Removes a built route, leaving the track of other routes sharing its
+    // cells in place.
+    pub fn demolish_route(&mut self, id: RouteId) -> NetworkEdit {
+        let mut edit = NetworkEdit::default();
+        self.remove_route(id, &mut edit);
+        edit
+    }
+
+    // Raises every built cell among `cells` one tier, where it can go higher.
+    pub fn upgrade(&mut self, mode: Mode, cells: &[(usize, usize)]) -> NetworkEdit {
+        let mut edit = NetworkEdit::default();
+        for &(row, col) in cells.iter() {
+            if self.level(mode)[row][col] == 0 {
+                continue;
+            }
+            if let Some(tier) = self.tier(mode)[row][col].next() {
+                self.set_tier(mode, (row, col), tier, &mut edit);
+            }
+        }
+        edit
+    }
+
+    // Removes a station together with the routes ending at it.
+    pub fn demolish_station(&mut self, station: (usize, usize)) -> NetworkEdit {
+        let mut edit = NetworkEdit::default();
+        let ending = self
+            .routes
+            .iter()
+            .filter(|(_, plan)| plan.a == station || plan.b == station)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in ending {
+            self.remove_route(id, &mut edit);
+        }
+        self.set_house(station, 0, &mut edit);
+        if self.stations.remove(&station) {
+            edit.stations_removed.push(station);
+        }
+        edit
+    }
+
+    // The most recently built route passing within `radius` of a cell.
+    pub fn route_near(&self, (row, col): (usize, usize), radius: usize) -> Option<RouteId> {
+        self.routes
+            .iter()
+            .rev()
+            .find(|(_, plan)| {
+                plan.cells()
+                    .iter()
+                    .any(|&(r, c)| r.abs_diff(row) <= radius && c.abs_diff(col) <= radius)
+            })
+            .map(|(&id, _)| id)
+    }
+
+    pub fn place_station(&mut self, station: (usize, usize)) -> NetworkEdit {
+        let mut edit = NetworkEdit::default();
+        self.add_station(station, &mut edit);
+        edit
+    }
+
+    // A new town gets a station at its centre.
+    pub fn found_town(&mut self, centre: (usize, usize)) -> NetworkEdit {
+        self.towns.push(centre);
+        self.place_station(centre)
+    }
+
+    pub fn build_house(&mut self, house: (usize, usize)) -> NetworkEdit {
+        let mut edit = NetworkEdit::default();
+        self.set_house(house, 1, &mut edit);
+        edit
+    }
+
+    // Settlement growth changes the network outside the history, so levels
+    // and houses are put back by how much the edit changed them rather than
+    // to what they were.
+    pub fn undo(&mut self, edit: &NetworkEdit) {
+        for &(mode, ((row, col), before, after)) in edit.levels.iter().rev() {
+            self.shift_level(mode, (row, col), before - after);
+        }
+        for &(mode, ((row, col), before, _)) in edit.tiers.iter().rev() {
+            self.tier_mut(mode)[row][col] = before;
+        }
+        for &((row, col), before, after) in edit.houses.iter().rev() {
+            self.shift_house((row, col), before - after);
+        }
+        for station in edit.stations_added.iter() {
+            self.stations.remove(station);
+        }
+        self.stations.extend(edit.stations_removed.iter().cloned());
+        for (id, _) in edit.routes_added.iter() {
+            self.routes.remove(id);
+        }
+        self.routes.extend(edit.routes_removed.iter().cloned());
+    }
+
+    pub fn redo(&mut self, edit: &NetworkEdit) {
+        for &(mode, ((row, col), before, after)) in edit.levels.iter() {
+            self.shift_level(mode, (row, col), after - before);
+        }
+        for &(mode, ((row, col), _, after)) in edit.tiers.iter() {
+            self.tier_mut(mode)[row][col] = after;
+        }
+        for &((row, col), before, after) in edit.houses.iter() {
+            self.shift_house((row, col), after - before);
+        }
+        for station in edit.stations_removed.iter() {
+            self.stations.remove(station);
+        }
+        self.stations.extend(edit.stations_added.iter().cloned());
+        for (id, _) in edit.routes_removed.iter() {
+            self.routes.remove(id);
+        }
+        self.routes.extend(edit.routes_added.iter().cloned());
+    }
+
+    pub fn level(&self, mode: Mode) -> &Vec<Vec<i32>> {
+        match mode {
+            Mode::Rail => &self.rail_level,
+            Mode::Road => &self.road_level,
+        }
+    }
+
+    fn level_mut(&mut self, mode: Mode) -> &mut Vec<Vec<i32>> {
+        match mode {
+            Mode::Rail => &mut self.rail_level,
+            Mode::Road => &mut self.road_level,
+        }
+    }
+
+    pub fn tier(&self, mode: Mode) -> &Vec<Vec<Tier>> {
+        match mode {
+            Mode::Rail => &self.rail_tier,
+            Mode::Road => &self.road_tier,
+        }
+    }
+
+    fn tier_mut(&mut self, mode: Mode) -> &mut Vec<Vec<Tier>> {
+        match mode {
+            Mode::Rail => &mut self.rail_tier,
+            Mode::Road => &mut self.road_tier,
+        }
+    }
+
+    // Where a road crosses a railway on the level.
+    pub fn is_crossing(&self, (row, col): (usize, usize)) -> bool {
+        self.rail_level[row][col] > 0 && self.road_level[row][col] > 0
+    }
+
+    fn set_level(
+        &mut self,
+        mode: Mode,
+        (row, col): (usize, usize),
+        level: i32,
+        edit: &mut NetworkEdit,
+    ) {
+        let before = self.level(mode)[row][col];
+        edit.levels.push((mode, ((row, col), before, level)));
+        self.level_mut(mode)[row][col] = level;
+    }
+
+    fn shift_level(&mut self, mode: Mode, (row, col): (usize, usize), change: i32) {
+        let level = &mut self.level_mut(mode)[row][col];
+        *level = (*level + change).max(0);
+    }
+
+    // A cell holds at most one house or station.
+    fn shift_house(&mut self, (row, col): (usize, usize), change: i32) {
+        let level = &mut self.house_level[row][col];
+        *level = (*level + change).clamp(0, 1);
+    }
+
+    fn set_tier(
+        &mut self,
+        mode: Mode,
+        (row, col): (usize, usize),
+        tier: Tier,
+        edit: &mut NetworkEdit,
+    ) {
+        let before = self.tier(mode)[row][col];
+        if before != tier {
+            edit.tiers.push((mode, ((row, col), before, tier)));
+            self.tier_mut(mode)[row][col] = tier;
+        }
+    }
+
+    fn set_house(&mut self, (row, col): (usize, usize), level: i32, edit: &mut NetworkEdit) {
+        edit.houses
+            .push(((row, col), self.house_level[row][col], level));
+        self.house_level[row][col] = level;
+    }
+
+    fn remove_route(&mut self, id: RouteId, edit: &mut NetworkEdit) {
+        let Some(plan) = self.routes.remove(&id) else {
+            return;
+        };
+        for (row, col) in plan.cells() {
+            let level = (self.level(plan.mode)[row][col] - 1).max(0);
+            self.set_level(plan.mode, (row, col), level, edit);
+            if level == 0 {
+                self.set_tier(plan.mode, (row, col), Tier::Basic, edit);
+            }
+        }
+        edit.routes_removed.push((id, plan));
+    }
+
+    fn add_station(&mut self, station: (usize, usize), edit: &mut NetworkEdit) {
+        self.set_house(station, 1, edit);
+        if self.stations.insert(station) {
+            edit.stations_added.push(station);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiers_go_up_to_major() {
+        assert_eq!(Tier::Basic.next(), Some(Tier::Improved));
+        assert_eq!(Tier::Improved.next(), Some(Tier::Major));
+        assert_eq!(Tier::Major.next(), None);
+    }
+
+    #[test]
+    fn commit_lays_track_and_stations() {
+        let mut network = Network::new(8, 8);
+        let edit = network.commit(&RoutePlan::straight(Mode::Rail, (0, 0), (0, 3)));
+        assert_eq!(network.rail_level[0], vec![1, 1, 1, 1, 0, 0, 0, 0]);
+        assert!(network.road_level[0].iter().all(|&level| level == 0));
+        assert!(network.stations.contains(&(0, 0)) && network.stations.contains(&(0, 3)));
+        assert_eq!(network.routes.len(), 1);
+        assert_eq!(edit.stations_added.len(), 2);
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_network() {
+        let mut network = Network::new(8, 8);
+        network.commit(&RoutePlan::straight(Mode::Rail, (0, 0), (0, 5)));
+        let edit = network.commit(&RoutePlan::straight(Mode::Rail, (0, 3), (0, 7)));
+        network.undo(&edit);
+        assert_eq!(network.rail_level[0], vec![1, 1, 1, 1, 1, 1, 0, 0]);
+        assert!(!network.stations.contains(&(0, 7)));
+        assert!(network.stations.contains(&(0, 0)));
+        assert_eq!(network.routes.len(), 1);
+        network.redo(&edit);
+        assert_eq!(network.rail_level[0], vec![1, 1, 1, 2, 2, 2, 1, 1]);
+        assert!(network.stations.contains(&(0, 7)));
+        assert_eq!(network.routes.len(), 2);
+    }
+
+    #[test]
+    fn undo_keeps_track_laid_outside_the_history() {
+        let mut network = Network::new(8, 8);
+        let edit = network.commit(&RoutePlan::straight(Mode::Road, (0, 0), (0, 5)));
+        network.lay(&RoutePlan::straight(Mode::Road, (0, 3), (0, 7)));
+        network.build_house((2, 2));
+        network.undo(&edit);
+        assert_eq!(network.road_level[0], vec![0, 0, 0, 1, 1, 1, 1, 1]);
+        assert_eq!(network.house_level[2][2], 1);
+        network.redo(&edit);
+        assert_eq!(network.road_level[0], vec![1, 1, 1, 2, 2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn admits_refuses_plans_through_houses_and_forbidden_cells() {
+        let mut network = Network::new(8, 8);
+        let road = RoutePlan::straight(Mode::Road, (0, 0), (0, 5));
+        network.place_station((0, 0));
+        assert!(network.admits(&road));
+        let house = network.build_house((0, 3));
+        assert!(!network.admits(&road));
+        network.undo(&house);
+        network.set_zone((0, 2), Zone::Forbidden);
+        assert!(!network.admits(&road));
+    }
+
+    #[test]
+    fn demolish_route_keeps_shared_track() {
+        let mut network = Network::new(8, 8);
+        network.commit(&RoutePlan::straight(Mode::Rail, (0, 0), (0, 5)));
+        let second = network.commit(&RoutePlan::straight(Mode::Rail, (0, 3), (0, 7)));
+        let (id, _) = second.routes_added[0];
+        network.upgrade(Mode::Rail, &[(0, 4), (0, 6)]);
+        network.demolish_route(id);
+        assert_eq!(network.rail_level[0][4], 1);
+        assert_eq!(network.rail_tier[0][4], Tier::Improved);
+        assert_eq!(network.rail_level[0][6], 0);
+        assert_eq!(network.rail_tier[0][6], Tier::Basic);
+        assert_eq!(network.routes.len(), 1);
+    }
+
+    #[test]
+    fn demolish_station_removes_routes_ending_there() {
+        let mut network = Network::new(8, 8);
+        network.commit(&RoutePlan::straight(Mode::Rail, (0, 0), (0, 5)));
+        network.commit(&RoutePlan::straight(Mode::Road, (2, 0), (2, 5)));
+        let edit = network.demolish_station((0, 5));
+        assert!(!network.stations.contains(&(0, 5)));
+        assert_eq!(network.routes.len(), 1);
+        assert!(network.rail_level[0].iter().all(|&level| level == 0));
+        network.undo(&edit);
+        assert!(network.stations.contains(&(0, 5)));
+        assert_eq!(network.routes.len(), 2);
+        assert!(network.rail_level[0][..6].iter().all(|&level| level == 1));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::demand::DemandMatrix;
+use crate::dijkstra::{Dijkstra, DijkstraUpdate, JobId};
+use crate::economy::{format_money, route_price, vehicle_price};
+use crate::graph::NetworkGraph;
+use crate::network::{Mode, Network, RoutePlan};
+use crossbeam_channel::Sender;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+// Station pairs routed in each round, the most promising by demand over
+// straight-line distance.
+const CANDIDATES_PER_ROUND: usize = 6;
+
+// Builds links between stations until the budget runs out, on a snapshot of
+// the network.
+#[derive(Clone)]
+pub struct PlanJob {
+    pub id: JobId,
+    pub mode: Mode,
+    pub network: Arc<Network>,
+    // The network of the job's mode as a graph, to tell which stations are
+    // already joined.
+    pub graph: NetworkGraph,
+    pub demand: DemandMatrix,
+    pub budget: f32,
+}
+
+// A link the planner chose, and why.
+pub struct PlannedLink {
+    pub plan: RoutePlan,
+    pub reason: String,
+}
+
+// Greedily builds the link with the best ratio of newly served demand to
+// price, merging the groups of stations it joins, and sends each one to the
+// UI as it goes. Returns how many links it built, or None if cancelled.
+pub fn autoplan(
+    dijkstra: &Dijkstra,
+    job: &PlanJob,
+    tx: &Sender<DijkstraUpdate>,
+    interrupted: &mut dyn FnMut() -> bool,
+) -> Option<usize> {
+    let mut network = (*job.network).clone();
+    let mut components = components(&job.graph, &network);
+    let mut stations = network.stations.iter().cloned().collect::<Vec<_>>();
+    stations.sort();
+    let total = job.demand.values().sum::<f32>();
+    let mut budget = job.budget;
+    println!(
+        "Autoplan #{}: budget {}, network serves {}/s of {}/s demand",
+        job.id,
+        format_money(budget),
+        format_money(served(&job.demand, &components)),
+        format_money(total)
+    );
+    let mut links = 0;
+    loop {
+        let mut candidates = Vec::new();
+        for (i, &a) in stations.iter().enumerate() {
+            for &b in stations.iter().skip(i + 1) {
+                let benefit = joined(&job.demand, &components, a, b);
+                if benefit <= 0.0 {
+                    continue;
+                }
+                let distance =
+                    ((a.0.abs_diff(b.0).pow(2) + a.1.abs_diff(b.1).pow(2)) as f32).sqrt();
+                candidates.push((benefit / distance, a, b, benefit));
+            }
+        }
+        candidates.sort_by(|x, y| y.0.total_cmp(&x.0));
+        candidates.truncate(CANDIDATES_PER_ROUND);
+        let mut best: Option<(f32, RoutePlan, f32, f32)> = None;
+        let mut evaluated = 0;
+        for &(_, a, b, benefit) in candidates.iter() {
+            if interrupted() {
+                return None;
+            }
+            let Some(plan) = dijkstra.plan_route(&network, job.mode, a, b, None) else {
+                continue;
+            };
+            let price = route_price(&plan.breakdown) + vehicle_price(job.mode);
+            if price > budget {
+                continue;
+            }
+            evaluated += 1;
+            let ratio = benefit / price;
+            if best.as_ref().is_none_or(|best| ratio > best.0) {
+                best = Some((ratio, plan, benefit, price));
+            }
+        }
+        let Some((ratio, plan, benefit, price)) = best else {
+            break;
+        };
+        budget -= price;
+        links += 1;
+        let reason = format!(
+            "{:?} to {:?}: serves {}/s more demand for {}, {:.2}/s per $1k, best of {} affordable links, {} left",
+            plan.a,
+            plan.b,
+            format_money(benefit),
+            format_money(price),
+            1000.0 * ratio,
+            evaluated,
+            format_money(budget)
+        );
+        println!("Autoplan #{} link {}: {}", job.id, links, reason);
+        network.commit(&plan);
+        let (from, to) = (components[&plan.a], components[&plan.b]);
+        for component in components.values_mut() {
+            if *component == to {
+                *component = from;
+            }
+        }
+        let _ = tx.send(DijkstraUpdate::Planned(PlannedLink { plan, reason }));
+    }
+    println!(
+        "Autoplan #{}: {} links for {}, network serves {}/s of {}/s demand",
+        job.id,
+        links,
+        format_money(job.budget - budget),
+        format_money(served(&job.demand, &components)),
+        format_money(total)
+    );
+    Some(links)
+}
+
+// Which group of stations joined by built routes each station is in,
+// following the graph's edges so that routes merely touching are apart.
+fn components(graph: &NetworkGraph, network: &Network) -> HashMap<(usize, usize), usize> {
+    let mut components = HashMap::new();
+    let mut stations = network.stations.iter().cloned().collect::<Vec<_>>();
+    stations.sort();
+    for (id, &station) in stations.iter().enumerate() {
+        if components.contains_key(&station) {
+            continue;
+        }
+        let mut visited = HashSet::from([station]);
+        let mut queue = VecDeque::from([station]);
+        while let Some(node) = queue.pop_front() {
+            if network.stations.contains(&node) {
+                components.insert(node, id);
+            }
+            for (_, edge) in graph.edges_at(node) {
+                let next = edge.other_end(node);
+                if visited.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+    components
+}
+
+// Demand between stations already joined to each other.
+fn served(demand: &DemandMatrix, components: &HashMap<(usize, usize), usize>) -> f32 {
+    demand
+        .iter()
+        .filter(|((from, to), _)| components.get(from) == components.get(to))
+        .map(|(_, amount)| amount)
+        .sum()
+}
+
+// Demand a link from a to b would newly serve: everything between the
+// stations joined to a and those joined to b.
+fn joined(
+    demand: &DemandMatrix,
+    components: &HashMap<(usize, usize), usize>,
+    a: (usize, usize),
+    b: (usize, usize),
+) -> f32 {
+    let (a, b) = (components[&a], components[&b]);
+    if a == b {
+        return 0.0;
+    }
+    demand
+        .iter()
+        .filter(|((from, to), _)| {
+            let pair = (components.get(from), components.get(to));
+            pair == (Some(&a), Some(&b)) || pair == (Some(&b), Some(&a))
+        })
+        .map(|(_, amount)| amount)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Railways from (0, 0) to (0, 4), from (1, 5) to (5, 5) touching it
+    // corner to corner, and from (0, 2) to (4, 2) branching off the first.
+    fn network() -> (NetworkGraph, Network) {
+        let terrain = Dijkstra::flat(8, 8);
+        let mut network = Network::new(8, 8);
+        let mut graph = NetworkGraph::default();
+        for (a, b) in [((0, 0), (0, 4)), ((1, 5), (5, 5)), ((0, 2), (4, 2))] {
+            let plan = RoutePlan::straight(Mode::Rail, a, b);
+            network.commit(&plan);
+            graph.add_route(&plan);
+            graph.refresh(plan.cells(), &network.stations, &terrain);
+        }
+        (graph, network)
+    }
+
+    #[test]
+    fn components_follow_track_not_touching_corners() {
+        let (graph, network) = network();
+        let components = components(&graph, &network);
+        assert_eq!(components.len(), 6);
+        let group = components[&(0, 0)];
+        for station in [(0, 2), (0, 4), (4, 2)] {
+            assert_eq!(components[&station], group);
+        }
+        assert_ne!(components[&(1, 5)], group);
+        assert_eq!(components[&(1, 5)], components[&(5, 5)]);
+    }
+
+    #[test]
+    fn joined_counts_demand_between_the_two_groups() {
+        let (graph, network) = network();
+        let components = components(&graph, &network);
+        let demand = DemandMatrix::from([
+            (((0, 0), (5, 5)), 3.0),
+            (((1, 5), (4, 2)), 2.0),
+            (((0, 0), (4, 2)), 7.0),
+        ]);
+        assert_eq!(joined(&demand, &components, (0, 4), (1, 5)), 5.0);
+        assert_eq!(joined(&demand, &components, (0, 0), (4, 2)), 0.0);
+        assert_eq!(served(&demand, &components), 7.0);
+    }
+}
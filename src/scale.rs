@@ -0,0 +1,56 @@
+// How big the map is on the ground: the width of a cell, and how many
+// metres one unit of the height map stands for.
+#[derive(Clone, Copy, Debug)]
+pub struct Scale {
+    pub metres_per_cell: f32,
+    pub metres_per_height: f32,
+}
+
+impl Default for Scale {
+    // About ten by eight kilometres, with some six hundred metres between
+    // the lowest and the highest point.
+    fn default() -> Self {
+        Scale {
+            metres_per_cell: 10.0,
+            metres_per_height: 1000.0,
+        }
+    }
+}
+
+impl Scale {
+    pub fn kilometres(&self, cells: f32) -> f32 {
+        cells * self.metres_per_cell / 1000.0
+    }
+
+    pub fn metres(&self, height: f32) -> f32 {
+        height * self.metres_per_height
+    }
+
+    // Rise over run in percent, for a height difference over a distance in
+    // cells.
+    pub fn grade(&self, height_diff: f32, cells: f32) -> f32 {
+        100.0 * self.metres(height_diff) / (cells * self.metres_per_cell)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_and_heights_convert_to_kilometres_and_metres() {
+        let scale = Scale::default();
+        assert_eq!(scale.kilometres(250.0), 2.5);
+        assert_eq!(scale.metres(0.6), 600.0);
+    }
+
+    #[test]
+    fn grade_is_rise_over_run_in_percent() {
+        let scale = Scale {
+            metres_per_cell: 20.0,
+            metres_per_height: 100.0,
+        };
+        assert_eq!(scale.grade(0.1, 5.0), 10.0);
+        assert_eq!(scale.grade(0.0, 5.0), 0.0);
+    }
+}
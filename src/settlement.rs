@@ -0,0 +1,235 @@
+use crate::dijkstra::Dijkstra;
+use crate::network::{Mode, Network, RoutePlan};
+use crate::suitability::Suitability;
+use crate::zones::Zone;
+use rand::prelude::*;
+
+// How many towns growth founds at most, and how far apart their centres are.
+pub const MAX_TOWNS: usize = 8;
+const TOWN_SPACING: usize = 120;
+// Chance per step of founding another town once there is one.
+const FOUNDING_CHANCE: f64 = 0.05;
+// Chance per step of a town laying a new street instead of building a house.
+const STREET_CHANCE: f64 = 0.1;
+// Cells from its centre within which a town builds houses and streets.
+pub const TOWN_RADIUS: usize = 24;
+// How much each neighbouring house adds to the pull of a cell.
+const NEIGHBOUR_WEIGHT: f32 = 0.3;
+
+// Something settlement growth built.
+pub enum Growth {
+    Town((usize, usize)),
+    House((usize, usize)),
+    // A road linking a new town to the nearest one.
+    Road(RoutePlan),
+    // A road from a town centre out to where it will grow, with no station at
+    // its far end.
+    Street(RoutePlan),
+}
+
+// One step of growth on a snapshot of the network: found a town at a good
+// site and link it to the nearest by road, or grow one of the towns.
+pub fn grow(dijkstra: &Dijkstra, network: &Network, rng: &mut impl Rng) -> Vec<Growth> {
+    if network.towns.is_empty()
+        || (network.towns.len() < MAX_TOWNS && rng.random_bool(FOUNDING_CHANCE))
+    {
+        return found(dijkstra, network);
+    }
+    let &centre = network.towns.choose(rng).unwrap();
+    if !rng.random_bool(STREET_CHANCE)
+        && let Some(house) = house_site(dijkstra, network, centre, rng)
+    {
+        return vec![Growth::House(house)];
+    }
+    street(dijkstra, network, centre, rng)
+        .map(Growth::Street)
+        .into_iter()
+        .collect()
+}
+
+fn found(dijkstra: &Dijkstra, network: &Network) -> Vec<Growth> {
+    let Some(site) = town_site(dijkstra, network) else {
+        return Vec::new();
+    };
+    let mut growth = vec![Growth::Town(site)];
+    let nearest = network
+        .towns
+        .iter()
+        .min_by_key(|town| town.0.abs_diff(site.0).pow(2) + town.1.abs_diff(site.1).pow(2));
+    if let Some(&nearest) = nearest
+        && let Some(plan) = dijkstra.plan_route(network, Mode::Road, site, nearest, None)
+    {
+        growth.push(Growth::Road(plan));
+    }
+    growth
+}
+
+// The most suitable site far enough from other towns and stations.
+fn town_site(dijkstra: &Dijkstra, network: &Network) -> Option<(usize, usize)> {
+    let avoid = network
+        .towns
+        .iter()
+        .chain(network.stations.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    Suitability::compute(dijkstra, network)
+        .best_sites(1, TOWN_SPACING, &avoid)
+        .pop()
+}
+
+// A free cell beside one of the town's roads, picked at random but favouring
+// cells close to the centre, on fast roads and among other houses.
+fn house_site(
+    dijkstra: &Dijkstra,
+    network: &Network,
+    centre: (usize, usize),
+    rng: &mut impl Rng,
+) -> Option<(usize, usize)> {
+    let mut candidates = Vec::new();
+    let rows =
+        centre.0.saturating_sub(TOWN_RADIUS)..(centre.0 + TOWN_RADIUS + 1).min(dijkstra.height);
+    for row in rows {
+        let cols =
+            centre.1.saturating_sub(TOWN_RADIUS)..(centre.1 + TOWN_RADIUS + 1).min(dijkstra.width);
+        for col in cols {
+            let distance_squared = row.abs_diff(centre.0).pow(2) + col.abs_diff(centre.1).pow(2);
+            if distance_squared > TOWN_RADIUS.pow(2)
+                || dijkstra.is_water[row][col]
+                || network.house_level[row][col] != 0
+                || network.rail_level[row][col] != 0
+                || network.road_level[row][col] != 0
+                || network.zones[row][col] != Zone::Free
+            {
+                continue;
+            }
+            let mut road_speed: f32 = 0.0;
+            let mut neighbours = 0;
+            for r in row.saturating_sub(1)..(row + 2).min(dijkstra.height) {
+                for c in col.saturating_sub(1)..(col + 2).min(dijkstra.width) {
+                    if network.road_level[r][c] != 0 {
+                        road_speed = road_speed.max(network.road_tier[r][c].speed());
+                    }
+                    if network.house_level[r][c] != 0 {
+                        neighbours += 1;
+                    }
+                }
+            }
+            if road_speed == 0.0 {
+                continue;
+            }
+            let distance = (distance_squared as f32).sqrt();
+            let access = road_speed / (1.0 + 4.0 * distance / TOWN_RADIUS as f32);
+            candidates.push((
+                (row, col),
+                access * (1.0 + NEIGHBOUR_WEIGHT * neighbours as f32),
+            ));
+        }
+    }
+    candidates
+        .choose_weighted(rng, |&(_, weight)| weight)
+        .ok()
+        .map(|&(cell, _)| cell)
+}
+
+// A road from the centre to a random point towards the edge of the town.
+fn street(
+    dijkstra: &Dijkstra,
+    network: &Network,
+    centre: (usize, usize),
+    rng: &mut impl Rng,
+) -> Option<RoutePlan> {
+    let angle = rng.random_range(0.0..std::f32::consts::TAU);
+    let length = rng.random_range(TOWN_RADIUS as f32 / 2.0..TOWN_RADIUS as f32);
+    let row = (centre.0 as f32 + length * angle.sin()).round() as isize;
+    let col = (centre.1 as f32 + length * angle.cos()).round() as isize;
+    if row < 0 || col < 0 || row >= dijkstra.height as isize || col >= dijkstra.width as isize {
+        return None;
+    }
+    let end = (row as usize, col as usize);
+    if dijkstra.is_water[end.0][end.1]
+        || network.house_level[end.0][end.1] != 0
+        || network.is_forbidden(end)
+    {
+        return None;
+    }
+    dijkstra.plan_route(network, Mode::Road, centre, end, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+
+    // A town at (32, 32) with a street running east from it, a pond south of
+    // the street, a no-build zone north of it and a house on it.
+    fn town() -> (Dijkstra, Network) {
+        let mut dijkstra = Dijkstra::flat(64, 64);
+        let mut network = Network::new(64, 64);
+        network.found_town((32, 32));
+        network.lay(&RoutePlan::straight(Mode::Road, (32, 32), (32, 48)));
+        for col in 32..40 {
+            dijkstra.is_water[33][col] = true;
+            network.set_zone((31, col), Zone::Forbidden);
+        }
+        network.build_house((31, 44));
+        (dijkstra, network)
+    }
+
+    #[test]
+    fn the_first_step_founds_a_town() {
+        // Suitability ranks heights, so the land needs some relief.
+        let mut dijkstra = Dijkstra::flat(64, 64);
+        dijkstra.height_map[0][0] = 0.01;
+        let network = Network::new(64, 64);
+        let growth = grow(&dijkstra, &network, &mut StdRng::seed_from_u64(1));
+        assert_eq!(growth.len(), 1);
+        assert!(matches!(growth[0], Growth::Town(_)));
+    }
+
+    #[test]
+    fn houses_go_on_free_dry_cells_beside_a_road() {
+        let (dijkstra, network) = town();
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..200 {
+            let (row, col) = house_site(&dijkstra, &network, (32, 32), &mut rng).unwrap();
+            assert!(!dijkstra.is_water[row][col]);
+            assert_eq!(network.house_level[row][col], 0);
+            assert_eq!(network.road_level[row][col], 0);
+            assert_eq!(network.zones[row][col], Zone::Free);
+            assert!((31..=33).contains(&row) && (31..=49).contains(&col));
+        }
+    }
+
+    #[test]
+    fn towns_without_roads_build_no_houses() {
+        let dijkstra = Dijkstra::flat(64, 64);
+        let mut network = Network::new(64, 64);
+        network.found_town((32, 32));
+        let mut rng = StdRng::seed_from_u64(3);
+        assert!(house_site(&dijkstra, &network, (32, 32), &mut rng).is_none());
+    }
+
+    #[test]
+    fn streets_end_on_the_map_on_dry_buildable_land() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let (dijkstra, network) = (Dijkstra::flat(8, 8), Network::new(8, 8));
+        for _ in 0..50 {
+            assert!(street(&dijkstra, &network, (4, 4), &mut rng).is_none());
+        }
+        let mut flooded = Dijkstra::flat(64, 64);
+        flooded.is_water = vec![vec![true; 64]; 64];
+        let mut forbidden = Network::new(64, 64);
+        for row in 0..64 {
+            for col in 0..64 {
+                forbidden.set_zone((row, col), Zone::Forbidden);
+            }
+        }
+        let (dry, free) = (Dijkstra::flat(64, 64), Network::new(64, 64));
+        for _ in 0..50 {
+            assert!(street(&flooded, &free, (32, 32), &mut rng).is_none());
+            assert!(street(&dry, &forbidden, (32, 32), &mut rng).is_none());
+            let plan = street(&dry, &free, (32, 32), &mut rng).unwrap();
+            assert_eq!(plan.a, (32, 32));
+        }
+    }
+}
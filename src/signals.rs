@@ -0,0 +1,275 @@
+use crate::graph::{Block, NetworkGraph};
+use crate::network::{Mode, Network};
+use crate::train::Train;
+use bevy::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+// Cells beyond its stopping distance a train reserves blocks ahead of it.
+pub const SIGNAL_SIGHT: f32 = 6.0;
+
+// Which train holds each block, and which trains are stuck waiting on each
+// other.
+#[derive(Resource, Default)]
+pub struct Signals {
+    pub claims: HashMap<Block, Entity>,
+    pub deadlocks: Vec<Vec<Entity>>,
+}
+
+// A block a train needs, how far it may go before entering it, and the path
+// index where it starts. The block the train is already in has no gap.
+struct Need {
+    block: Block,
+    gap: Option<f32>,
+    index: usize,
+}
+
+impl Signals {
+    // Reserves blocks for every train and sets how far each may go. A train
+    // keeps its blocks until it no longer needs them, and one that finds a
+    // block taken waits before it, or takes a free parallel edge instead.
+    // Road vehicles claim nothing but stop short of level crossings whose
+    // rail block is taken. Returns whether the set of deadlocked trains
+    // changed.
+    pub fn update(
+        &mut self,
+        graph: &NetworkGraph,
+        network: &Network,
+        trains: &mut [(Entity, Mut<Train>)],
+        seconds: f32,
+    ) -> bool {
+        trains.sort_by_key(|(entity, _)| *entity);
+        let needs = trains
+            .iter()
+            .map(|(_, train)| match train.mode {
+                Mode::Rail => Self::needs(graph, train, seconds),
+                Mode::Road => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        self.claims.retain(|block, holder| {
+            trains.iter().zip(needs.iter()).any(|((entity, _), needs)| {
+                entity == holder && needs.iter().any(|need| need.block == *block)
+            })
+        });
+        let mut waiting = BTreeMap::new();
+        for ((entity, train), needs) in trains.iter_mut().zip(needs.iter()) {
+            train.signal_gap = None;
+            for need in needs.iter() {
+                match self.claims.get(&need.block).copied() {
+                    Some(holder) if holder != *entity && need.gap.is_some() => {
+                        // The rest of the path changed, so look again next time.
+                        if self.take_loop(graph, *entity, train, need) {
+                            break;
+                        }
+                        train.signal_gap = need.gap;
+                        waiting.insert(*entity, holder);
+                        break;
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.claims.insert(need.block, *entity);
+                    }
+                }
+            }
+        }
+        for (_, train) in trains.iter_mut() {
+            if train.mode == Mode::Road {
+                train.signal_gap = self.crossing_gap(graph, network, train, seconds);
+            }
+        }
+        let deadlocks = deadlocks(&waiting);
+        let changed = deadlocks != self.deadlocks;
+        self.deadlocks = deadlocks;
+        changed
+    }
+
+    // The blocks a train's cars are in, and those it could reach before
+    // stopping.
+    fn needs(graph: &NetworkGraph, train: &Train, seconds: f32) -> Vec<Need> {
+        let mut needs = Vec::<Need>::new();
+        let mut push = |block: Option<Block>, gap: Option<f32>, index: usize| {
+            if let Some(block) = block
+                && !needs.iter().any(|need| need.block == block)
+            {
+                needs.push(Need { block, gap, index });
+            }
+        };
+        let behind = train.behind();
+        push(graph.block_at(train.path[behind]), None, behind);
+        let (low, high) = train.extent();
+        let first = train
+            .offsets
+            .partition_point(|&offset| offset <= low)
+            .saturating_sub(1);
+        let last = train
+            .offsets
+            .partition_point(|&offset| offset < high)
+            .min(train.path.len() - 1);
+        for index in first..=last {
+            push(graph.block_at(train.path[index]), None, index);
+        }
+        if train.stopped() {
+            return needs;
+        }
+        let sight = sight(train, seconds);
+        let mut gap = 0.0;
+        for (index, distance) in train.ahead() {
+            if distance > sight {
+                break;
+            }
+            push(graph.block_at(train.path[index]), Some(gap), index);
+            gap = distance;
+        }
+        needs
+    }
+
+    // How far a road vehicle may go before the first level crossing ahead
+    // whose rail block a train holds.
+    fn crossing_gap(
+        &self,
+        graph: &NetworkGraph,
+        network: &Network,
+        train: &Train,
+        seconds: f32,
+    ) -> Option<f32> {
+        if train.stopped() {
+            return None;
+        }
+        let sight = sight(train, seconds);
+        let mut gap = 0.0;
+        for (index, distance) in train.ahead() {
+            if distance > sight {
+                break;
+            }
+            let cell = train.path[index];
+            if network.is_crossing(cell)
+                && graph
+                    .block_at(cell)
+                    .is_some_and(|block| self.claims.contains_key(&block))
+            {
+                return Some(gap);
+            }
+            gap = distance;
+        }
+        None
+    }
+
+    // Moves the train onto a free edge running alongside the taken one.
+    fn take_loop(
+        &mut self,
+        graph: &NetworkGraph,
+        entity: Entity,
+        train: &mut Train,
+        need: &Need,
+    ) -> bool {
+        let Block::Edge(id) = need.block else {
+            return false;
+        };
+        let Some((free, edge)) = graph
+            .parallel_edges(id)
+            .find(|(other, _)| !self.claims.contains_key(&Block::Edge(*other)))
+        else {
+            return false;
+        };
+        // The nodes either side of the taken edge, in path order.
+        let (from, to) = if train.forward {
+            let from = need.index.saturating_sub(1);
+            let Some(to) =
+                (need.index..train.path.len()).find(|&i| graph.nodes.contains_key(&train.path[i]))
+            else {
+                return false;
+            };
+            (from, to)
+        } else {
+            let to = (need.index + 1).min(train.path.len() - 1);
+            let Some(from) = (0..=need.index)
+                .rev()
+                .find(|&i| graph.nodes.contains_key(&train.path[i]))
+            else {
+                return false;
+            };
+            (from, to)
+        };
+        let mut cells = edge.cells.clone();
+        if cells[0] != train.path[from] {
+            cells.reverse();
+        }
+        if cells[0] != train.path[from] || cells[cells.len() - 1] != train.path[to] {
+            return false;
+        }
+        train.splice(from, to, cells);
+        self.claims.insert(Block::Edge(free), entity);
+        true
+    }
+}
+
+// How far ahead a train looks for blocks: its stopping distance, one more
+// step, and a margin.
+fn sight(train: &Train, seconds: f32) -> f32 {
+    train.stopping_distance + train.speed * seconds + SIGNAL_SIGHT
+}
+
+// Groups of trains each waiting for a block held by the next.
+fn deadlocks(waiting: &BTreeMap<Entity, Entity>) -> Vec<Vec<Entity>> {
+    let mut deadlocks = Vec::new();
+    for &start in waiting.keys() {
+        let mut cycle = vec![start];
+        let mut current = start;
+        while let Some(&next) = waiting.get(&current) {
+            if next == start {
+                cycle.sort();
+                if !deadlocks.contains(&cycle) {
+                    deadlocks.push(cycle.clone());
+                }
+                break;
+            }
+            if cycle.contains(&next) {
+                break;
+            }
+            cycle.push(next);
+            current = next;
+        }
+    }
+    deadlocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiting(pairs: &[(u32, u32)]) -> BTreeMap<Entity, Entity> {
+        pairs
+            .iter()
+            .map(|&(train, holder)| (Entity::from_raw(train), Entity::from_raw(holder)))
+            .collect()
+    }
+
+    #[test]
+    fn trains_waiting_on_each_other_are_deadlocked() {
+        let found = deadlocks(&waiting(&[(1, 2), (2, 3), (3, 1)]));
+        assert_eq!(
+            found,
+            vec![vec![
+                Entity::from_raw(1),
+                Entity::from_raw(2),
+                Entity::from_raw(3)
+            ]]
+        );
+    }
+
+    #[test]
+    fn a_queue_of_trains_is_not_a_deadlock() {
+        assert!(deadlocks(&waiting(&[(1, 2), (2, 3)])).is_empty());
+    }
+
+    #[test]
+    fn trains_queued_behind_a_deadlock_are_not_part_of_it() {
+        let found = deadlocks(&waiting(&[(1, 2), (2, 1), (3, 1), (4, 5), (5, 4)]));
+        assert_eq!(
+            found,
+            vec![
+                vec![Entity::from_raw(1), Entity::from_raw(2)],
+                vec![Entity::from_raw(4), Entity::from_raw(5)]
+            ]
+        );
+    }
+}
@@ -1,7 +1,12 @@
-use crate::dijkstra::{Dijkstra, DijkstraUpdate};
+use crate::dijkstra::{CostModel, Dijkstra, Water, segment_cells};
+use crate::graph::NetworkGraph;
+use crate::network::{Mode, Network, NetworkEdit, RouteId, RoutePlan, Tier};
+use crate::scale::Scale;
 use crate::terrain::height_map;
+use crate::zones::{self, Zone};
 use bevy::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 #[derive(Resource)]
 pub struct ImageHandle(pub Handle<Image>);
@@ -11,256 +16,431 @@ pub struct GameTime {
     pub _time: f32,
 }
 
+const CROSSING_COLOR: [u8; 3] = [255, 220, 0];
+const HOUSE_COLOR: [u8; 3] = [210, 170, 120];
+
+// A pixel of the map image that changed from the first colour to the second.
+pub type PixelChange = ((usize, usize), [u8; 3], [u8; 3]);
+
+// A change to the network together with the pixels it repainted.
+#[derive(Clone, Default)]
+pub struct Edit {
+    pub network: NetworkEdit,
+    pub pixels: Vec<PixelChange>,
+}
+
 #[derive(Resource)]
 pub struct MapState {
     pub dijkstra: Dijkstra,
-    stations: HashSet<(usize, usize)>,
+    // The land before lakes are filled, which terraforming reshapes.
+    pub ground: Vec<Vec<f32>>,
+    // The only copy of the network that is ever modified. Route jobs get a
+    // cheap snapshot of it, and commits copy it only if a job still holds one.
+    pub network: Arc<Network>,
+    // The built railways and roads as nodes and edges, kept in step with the
+    // network.
+    pub graph: NetworkGraph,
+    pub road_graph: NetworkGraph,
     min_height: f32,
     max_height: f32,
 }
 
 impl MapState {
     pub fn new(width: usize, height: usize) -> Self {
-        let mut height_map = height_map(width, height);
-        let mut points_with_height = Vec::new();
-        let mut lake_id: Vec<Vec<usize>> = vec![vec![0; width]; height];
-        let mut actual_lake_id = HashMap::new();
-        let mut overflown_lakes = HashSet::new();
-        actual_lake_id.insert(0, 0);
-        let mut lake_level = HashMap::new();
-        let mut next_lake_id = 1;
-        let mut is_water = vec![vec![false; width]; height];
-        for col in 0..width {
-            for row in 0..height {
-                points_with_height.push((height_map[row][col], row, col));
-            }
+        let ground = height_map(width, height);
+        let (height_map, is_water) = extract_water(&ground, false);
+        let (min_height, max_height) = height_range(&height_map);
+        let dijkstra = Dijkstra {
+            width,
+            height,
+            height_map,
+            is_water,
+            scale: Scale::default(),
+            rail_costs: CostModel::rail(),
+            road_costs: CostModel::road(),
+        };
+        let mut network = Network::new(width, height);
+        if let Some(zones) = zones::load(&zones::path(width, height), width, height) {
+            network.load_zones(zones);
         }
-        points_with_height.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-        let is_edge =
-            |row: usize, col: usize| row == 0 || col == 0 || row == height - 1 || col == width - 1;
-        // let mut points_handled = 0;
-        for (h, row, col) in points_with_height {
-            // points_handled += 1;
-            let neighbor_lake_ids = [
-                (row.saturating_sub(1), col),
-                ((row + 1).min(height - 1), col),
-                (row, col.saturating_sub(1)),
-                (row, (col + 1).min(width - 1)),
-            ]
-            .iter()
-            .filter(|&(r, c)| height_map[*r][*c] < h)
-            .map(|&(r, c)| actual_lake_id[&lake_id[r][c]])
-            .collect::<HashSet<_>>();
-            if is_edge(row, col) {
-                lake_id[row][col] = 0;
-                for lake_id in neighbor_lake_ids.iter() {
-                    if *lake_id == 0 {
-                        continue;
-                    }
-                    overflown_lakes.insert(*lake_id);
-                }
-                continue;
-            }
-            if neighbor_lake_ids.is_empty() {
-                lake_id[row][col] = next_lake_id;
-                is_water[row][col] = true;
-                // println!("Lakes: {}", next_lake_id);
-                actual_lake_id.insert(next_lake_id, next_lake_id);
-                lake_level.insert(next_lake_id, h);
-                next_lake_id += 1;
-                continue;
-            }
-            if neighbor_lake_ids.contains(&0) {
-                let mut river = false;
-                for other_lake_id in neighbor_lake_ids.iter() {
-                    if *other_lake_id == 0 {
-                        continue;
-                    }
-                    if overflown_lakes.contains(other_lake_id) {
-                        continue;
-                    }
-                    overflown_lakes.insert(*other_lake_id);
-                    river = true;
-                }
-                if river {
-                    let mut r = row;
-                    let mut c = col;
-                    while !is_edge(r, c) {
-                        is_water[r][c] = true;
-                        // let neighbors = [
-                        //     (r.saturating_sub(1), c),
-                        //     ((r + 1).min(height - 1), c),
-                        //     (r, c.saturating_sub(1)),
-                        //     (r, (c + 1).min(width - 1)),
-                        // ];
-                        let mut neighbors = Vec::new();
-                        for dr in -1..=1 {
-                            for dc in -1..=1 {
-                                neighbors
-                                    .push(((r as isize + dr) as usize, (c as isize + dc) as usize));
-                            }
-                        }
-                        let non_same_lake_lower_neighbors = neighbors
-                            .iter()
-                            .filter(|&&(nr, nc)| {
-                                height_map[nr][nc] < height_map[r][c]
-                                    && (actual_lake_id[&lake_id[nr][nc]] == 0
-                                        || !neighbor_lake_ids
-                                            .contains(&actual_lake_id[&lake_id[nr][nc]]))
-                            })
-                            .collect::<Vec<_>>();
-                        if non_same_lake_lower_neighbors.is_empty() {
-                            break;
-                        }
-                        let lowest_neighbor = non_same_lake_lower_neighbors
-                            .iter()
-                            .map(|&&(nr, nc)| {
-                                (
-                                    (height_map[nr][nc] - height_map[r][c])
-                                        / ((nr as f32 - r as f32) * (nr as f32 - r as f32)
-                                            + (nc as f32 - c as f32) * (nc as f32 - c as f32))
-                                            .sqrt(),
-                                    nr,
-                                    nc,
-                                )
-                            })
-                            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
-                            .unwrap();
-                        r = lowest_neighbor.1;
-                        c = lowest_neighbor.2;
-                    }
-                }
-                lake_id[row][col] = 0;
-                continue;
+        MapState {
+            dijkstra,
+            ground,
+            network: Arc::new(network),
+            graph: NetworkGraph::default(),
+            road_graph: NetworkGraph::default(),
+            min_height,
+            max_height,
+        }
+    }
+
+    // Dry land of the given heights with nothing built and no zones, for
+    // tests.
+    #[cfg(test)]
+    pub fn from_heights(height_map: Vec<Vec<f32>>) -> Self {
+        let (height, width) = (height_map.len(), height_map[0].len());
+        let (min_height, max_height) = height_range(&height_map);
+        let mut dijkstra = Dijkstra::flat(width, height);
+        dijkstra.height_map = height_map.clone();
+        MapState {
+            dijkstra,
+            ground: height_map,
+            network: Arc::new(Network::new(width, height)),
+            graph: NetworkGraph::default(),
+            road_graph: NetworkGraph::default(),
+            min_height,
+            max_height,
+        }
+    }
+
+    // Builds a planned route and draws it.
+    pub fn commit(&mut self, plan: &RoutePlan, image: &mut Image) -> Edit {
+        let network = Arc::make_mut(&mut self.network).commit(plan);
+        self.draw_route(plan, network, image)
+    }
+
+    // Builds a planned route without stations at its ends and draws it.
+    pub fn lay(&mut self, plan: &RoutePlan, image: &mut Image) -> Edit {
+        let network = Arc::make_mut(&mut self.network).lay(plan);
+        self.draw_route(plan, network, image)
+    }
+
+    fn draw_route(&mut self, plan: &RoutePlan, network: NetworkEdit, image: &mut Image) -> Edit {
+        let mut edit = Edit {
+            network,
+            pixels: Vec::new(),
+        };
+        self.update_graph(&edit.network, true);
+        for (start, end) in plan.path.iter() {
+            let color = self.segment_color(plan.mode, *start, *end);
+            for cell in segment_cells(*start, *end) {
+                let color = self.cell_color(plan.mode, cell, color);
+                paint(image, cell, color, &mut edit.pixels);
             }
-            let non_overflow_neighbor_lake_ids = neighbor_lake_ids
-                .iter()
-                .filter(|&&x| !overflown_lakes.contains(&x))
-                .cloned()
-                .collect::<HashSet<_>>();
-            if non_overflow_neighbor_lake_ids.is_empty() {
-                lake_id[row][col] = 0;
-                continue;
+        }
+        for station in [plan.a, plan.b] {
+            if self.network.stations.contains(&station) {
+                self.draw_station(station, image, &mut edit.pixels);
             }
-            // This point is part of one ore more lakes.
-            let smallest_lake_id = non_overflow_neighbor_lake_ids
+        }
+        edit
+    }
+
+    pub fn found_town(&mut self, centre: (usize, usize), image: &mut Image) -> Edit {
+        let mut edit = Edit {
+            network: Arc::make_mut(&mut self.network).found_town(centre),
+            pixels: Vec::new(),
+        };
+        self.update_graph(&edit.network, true);
+        self.draw_station(centre, image, &mut edit.pixels);
+        edit
+    }
+
+    pub fn build_house(&mut self, house: (usize, usize), image: &mut Image) -> Edit {
+        let mut edit = Edit {
+            network: Arc::make_mut(&mut self.network).build_house(house),
+            pixels: Vec::new(),
+        };
+        self.update_graph(&edit.network, true);
+        paint(image, house, HOUSE_COLOR, &mut edit.pixels);
+        edit
+    }
+
+    pub fn place_station(&mut self, station: (usize, usize), image: &mut Image) -> Edit {
+        let mut edit = Edit {
+            network: Arc::make_mut(&mut self.network).place_station(station),
+            pixels: Vec::new(),
+        };
+        self.update_graph(&edit.network, true);
+        self.draw_station(station, image, &mut edit.pixels);
+        edit
+    }
+
+    pub fn demolish_route(&mut self, id: RouteId, image: &mut Image) -> Edit {
+        let mut edit = Edit {
+            network: Arc::make_mut(&mut self.network).demolish_route(id),
+            pixels: Vec::new(),
+        };
+        self.update_graph(&edit.network, true);
+        let cells = edit
+            .network
+            .routes_removed
+            .iter()
+            .flat_map(|(_, plan)| plan.cells())
+            .collect::<Vec<_>>();
+        self.redraw(&cells, image, &mut edit.pixels);
+        edit
+    }
+
+    // Raises the built cells among `cells` one tier and redraws them.
+    pub fn upgrade(&mut self, mode: Mode, cells: &[(usize, usize)], image: &mut Image) -> Edit {
+        let mut edit = Edit {
+            network: Arc::make_mut(&mut self.network).upgrade(mode, cells),
+            pixels: Vec::new(),
+        };
+        self.redraw(cells, image, &mut edit.pixels);
+        edit
+    }
+
+    pub fn demolish_station(&mut self, station: (usize, usize), image: &mut Image) -> Edit {
+        let mut edit = Edit {
+            network: Arc::make_mut(&mut self.network).demolish_station(station),
+            pixels: Vec::new(),
+        };
+        self.update_graph(&edit.network, true);
+        let mut cells = self.station_disc(station);
+        cells.extend(
+            edit.network
+                .routes_removed
                 .iter()
-                .cloned()
-                .min()
-                .unwrap();
-            lake_id[row][col] = smallest_lake_id;
-            is_water[row][col] = true;
-            lake_level.insert(smallest_lake_id, h);
-            for other_lake_id in non_overflow_neighbor_lake_ids {
-                if other_lake_id == smallest_lake_id {
+                .flat_map(|(_, plan)| plan.cells()),
+        );
+        self.redraw(&cells, image, &mut edit.pixels);
+        edit
+    }
+
+    pub fn undo(&mut self, edit: &Edit, image: &mut Image) {
+        Arc::make_mut(&mut self.network).undo(&edit.network);
+        self.update_graph(&edit.network, false);
+        self.repaint(edit, image);
+    }
+
+    pub fn redo(&mut self, edit: &Edit, image: &mut Image) {
+        Arc::make_mut(&mut self.network).redo(&edit.network);
+        self.update_graph(&edit.network, true);
+        self.repaint(edit, image);
+    }
+
+    // Redraws the cells an edit painted from what is on them now, as the
+    // terrain under them may have been reshaped since.
+    fn repaint(&self, edit: &Edit, image: &mut Image) {
+        let cells = edit
+            .pixels
+            .iter()
+            .map(|&(cell, _, _)| cell)
+            .collect::<Vec<_>>();
+        self.redraw(&cells, image, &mut Vec::new());
+    }
+
+    // Applies an edit to the graph, forwards or backwards, touching only the
+    // cells it changed.
+    fn update_graph(&mut self, edit: &NetworkEdit, forward: bool) {
+        let (added, removed) = if forward {
+            (&edit.routes_added, &edit.routes_removed)
+        } else {
+            (&edit.routes_removed, &edit.routes_added)
+        };
+        for (_, plan) in removed.iter() {
+            self.graph_mut(plan.mode).remove_route(plan);
+        }
+        for (_, plan) in added.iter() {
+            self.graph_mut(plan.mode).add_route(plan);
+        }
+        let changed = edit
+            .levels
+            .iter()
+            .map(|(_, change)| change)
+            .chain(edit.houses.iter())
+            .map(|&(cell, _, _)| cell)
+            .chain(edit.stations_added.iter().cloned())
+            .chain(edit.stations_removed.iter().cloned())
+            .collect::<Vec<_>>();
+        for mode in [Mode::Rail, Mode::Road] {
+            let mut graph = std::mem::take(self.graph_mut(mode));
+            graph.refresh(
+                changed.iter().cloned(),
+                &self.network.stations,
+                &self.dijkstra,
+            );
+            *self.graph_mut(mode) = graph;
+        }
+    }
+
+    pub fn graph(&self, mode: Mode) -> &NetworkGraph {
+        match mode {
+            Mode::Rail => &self.graph,
+            Mode::Road => &self.road_graph,
+        }
+    }
+
+    fn graph_mut(&mut self, mode: Mode) -> &mut NetworkGraph {
+        match mode {
+            Mode::Rail => &mut self.graph,
+            Mode::Road => &mut self.road_graph,
+        }
+    }
+
+    fn draw_station(
+        &self,
+        station: (usize, usize),
+        image: &mut Image,
+        pixels: &mut Vec<PixelChange>,
+    ) {
+        for cell in self.station_disc(station) {
+            paint(image, cell, [255, 0, 0], pixels);
+        }
+    }
+
+    // Cells covered by the marker of a station, clamped to the map.
+    fn station_disc(&self, (row, col): (usize, usize)) -> Vec<(usize, usize)> {
+        let mut cells = Vec::new();
+        for dr in -4..=4 {
+            for dc in -4..=4 {
+                if dr * dr + dc * dc > 16 {
                     continue;
                 }
-                actual_lake_id.insert(other_lake_id, smallest_lake_id);
+                let r = (row as isize + dr).clamp(0, self.dijkstra.height as isize - 1) as usize;
+                let c = (col as isize + dc).clamp(0, self.dijkstra.width as isize - 1) as usize;
+                cells.push((r, c));
             }
         }
+        cells
+    }
 
-        println!("Lakes found: {}", next_lake_id);
+    // Railways are bright and roads grey, both darker the steeper they are:
+    // up to 2.5%, 5%, 10% and beyond.
+    fn segment_color(&self, mode: Mode, start: (usize, usize), end: (usize, usize)) -> [u8; 3] {
+        let dist = (((start.0 as isize - end.0 as isize).pow(2)
+            + (start.1 as isize - end.1 as isize).pow(2)) as f32)
+            .sqrt();
+        let height_diff =
+            self.dijkstra.height_map[start.0][start.1] - self.dijkstra.height_map[end.0][end.1];
+        let grade = self.dijkstra.scale.grade(height_diff.abs(), dist);
+        match (mode, grade) {
+            (Mode::Rail, 0.0..=2.5) => [255, 255, 255],
+            (Mode::Rail, 2.5..=5.0) => [255, 128, 0],
+            (Mode::Rail, 5.0..=10.0) => [255, 0, 0],
+            (Mode::Rail, _) => [255, 0, 255],
+            (Mode::Road, 0.0..=2.5) => [170, 170, 170],
+            (Mode::Road, 2.5..=5.0) => [140, 120, 100],
+            (Mode::Road, 5.0..=10.0) => [120, 90, 70],
+            (Mode::Road, _) => [100, 70, 100],
+        }
+    }
 
-        for row in 0..height {
-            for col in 0..width {
-                if lake_id[row][col] != 0 {
-                    height_map[row][col] = lake_level[&actual_lake_id[&lake_id[row][col]]];
+    // Level crossings stand out, and higher tiers are tinted: blue for
+    // electrified railways, dark for paved roads.
+    fn cell_color(&self, mode: Mode, (row, col): (usize, usize), color: [u8; 3]) -> [u8; 3] {
+        if self.network.is_crossing((row, col)) {
+            return CROSSING_COLOR;
+        }
+        let tint = match mode {
+            Mode::Rail => [0, 120, 255],
+            Mode::Road => [40, 40, 40],
+        };
+        let t = match self.network.tier(mode)[row][col] {
+            Tier::Basic => 0.0,
+            Tier::Improved => 0.35,
+            Tier::Major => 0.7,
+        };
+        [0, 1, 2].map(|i| (color[i] as f32 + t * (tint[i] as f32 - color[i] as f32)) as u8)
+    }
+
+    // Repaints cells from what is currently on them: stations on top, then
+    // level crossings, then routes in the order they were built, then
+    // houses, then the terrain.
+    fn redraw(&self, cells: &[(usize, usize)], image: &mut Image, pixels: &mut Vec<PixelChange>) {
+        let mut colors = HashMap::new();
+        for plan in self.network.routes.values() {
+            for &(start, end) in plan.path.iter() {
+                let color = self.segment_color(plan.mode, start, end);
+                for cell in segment_cells(start, end) {
+                    colors.insert(cell, self.cell_color(plan.mode, cell, color));
                 }
             }
         }
+        for &station in self.network.stations.iter() {
+            for cell in self.station_disc(station) {
+                colors.insert(cell, [255, 0, 0]);
+            }
+        }
+        for &cell in cells.iter() {
+            let color = colors.get(&cell).cloned().unwrap_or_else(|| {
+                if self.network.house_level[cell.0][cell.1] != 0 {
+                    HOUSE_COLOR
+                } else {
+                    self.terrain_color(cell.1, cell.0)
+                }
+            });
+            paint(image, cell, color, pixels);
+        }
+    }
 
-        let min_height = height_map
-            .iter()
-            .flatten()
-            .cloned()
-            .reduce(f32::min)
-            .unwrap();
-        let max_height = height_map
-            .iter()
-            .flatten()
-            .cloned()
-            .reduce(f32::max)
-            .unwrap();
-        let dijkstra = Dijkstra {
-            width,
-            height,
-            height_map,
-            is_water,
-            road_level: vec![vec![0; width]; height],
-            house_level: vec![vec![0; width]; height],
-        };
-        MapState {
-            dijkstra,
-            min_height,
-            max_height,
-            stations: HashSet::new(),
+    // Shows cells whose ground was reshaped at their new height until the
+    // water is extracted again.
+    pub fn reshape(&mut self, cells: &[(usize, usize)], image: &mut Image) {
+        for &(row, col) in cells.iter() {
+            self.dijkstra.height_map[row][col] = self.ground[row][col];
         }
+        self.redraw(cells, image, &mut Vec::new());
     }
 
-    pub fn process_dijsktra_update(
-        &mut self,
-        update: &DijkstraUpdate,
-        image: &mut Image,
-    ) -> Vec<(usize, usize)> {
-        let mut path = Vec::new();
-        for (start, end) in update.path.iter() {
-            let dist = (((start.0 as isize - end.0 as isize).pow(2)
-                + (start.1 as isize - end.1 as isize).pow(2)) as f32)
-                .sqrt();
-            let height_diff =
-                self.dijkstra.height_map[start.0][start.1] - self.dijkstra.height_map[end.0][end.1];
-            let steepness = 2000.0 * (height_diff / dist).abs();
-            let (r, g, b) = match steepness {
-                0.0..=0.5 => (255, 255, 255),
-                0.5..=1.0 => (255, 128, 0),
-                1.0..=2.0 => (255, 0, 0),
-                _ => (255, 0, 255),
-            };
-            let (mut row, mut col) = *start;
-            loop {
-                self.dijkstra.road_level[row][col] += 1;
-                let pixel = image
-                    .pixel_bytes_mut(UVec3::new(col as u32, row as u32, 0))
-                    .unwrap();
-                pixel[0] = r as u8;
-                pixel[1] = g as u8;
-                pixel[2] = b as u8;
-                if (row, col) == *end {
-                    break;
-                }
-                row = (row as isize + (end.0 as isize - row as isize).signum()) as usize;
-                col = (col as isize + (end.1 as isize - col as isize).signum()) as usize;
-                path.push((row, col));
-            }
+    // Takes the lakes and rivers the worker found on the reshaped ground,
+    // repaints the map and measures the built edges again. Basins drain
+    // across the whole map, so all of it is redone. Track the water now
+    // covers is kept and counts as bridges from then on, as if it had been
+    // built over the water, and stations stay where they are.
+    pub fn set_water(&mut self, water: Water, image: &mut Image) {
+        (self.min_height, self.max_height) = height_range(&water.height_map);
+        self.dijkstra.height_map = water.height_map;
+        self.dijkstra.is_water = water.is_water;
+        self.render_image(image);
+        let network = &self.network;
+        let mut built = (0..self.dijkstra.height)
+            .flat_map(|row| (0..self.dijkstra.width).map(move |col| (row, col)))
+            .filter(|&(row, col)| {
+                network.rail_level[row][col] != 0
+                    || network.road_level[row][col] != 0
+                    || network.house_level[row][col] != 0
+            })
+            .collect::<Vec<_>>();
+        for &station in network.stations.iter() {
+            built.extend(self.station_disc(station));
         }
-        for &(row, col) in update.houses.iter() {
-            self.stations.insert((row, col));
+        self.redraw(&built, image, &mut Vec::new());
+        for mode in [Mode::Rail, Mode::Road] {
+            let mut graph = std::mem::take(self.graph_mut(mode));
+            graph.refresh(
+                built.iter().cloned(),
+                &self.network.stations,
+                &self.dijkstra,
+            );
+            *self.graph_mut(mode) = graph;
         }
-        for &(row, col) in self.stations.iter() {
-            for dr in -4..=4 {
-                for dc in -4..=4 {
-                    if dr * dr + dc * dc > 16 {
-                        continue;
-                    }
-                    let r =
-                        (row as isize + dr).clamp(0, self.dijkstra.height as isize - 1) as usize;
-                    let c = (col as isize + dc).clamp(0, self.dijkstra.width as isize - 1) as usize;
-                    let pixel = image
-                        .pixel_bytes_mut(UVec3::new(c as u32, r as u32, 0))
-                        .unwrap();
-                    pixel[0] = 255;
-                    pixel[1] = 0;
-                    pixel[2] = 0;
+    }
+
+    // Sets the zone of every cell within `radius` of the centre, returning
+    // the cells that changed.
+    pub fn paint_zone(
+        &mut self,
+        (row, col): (usize, usize),
+        radius: usize,
+        zone: Zone,
+    ) -> Vec<(usize, usize)> {
+        let network = Arc::make_mut(&mut self.network);
+        let mut changed = Vec::new();
+        for r in row.saturating_sub(radius)..(row + radius + 1).min(self.dijkstra.height) {
+            for c in col.saturating_sub(radius)..(col + radius + 1).min(self.dijkstra.width) {
+                if r.abs_diff(row).pow(2) + c.abs_diff(col).pow(2) <= radius.pow(2)
+                    && network.set_zone((r, c), zone)
+                {
+                    changed.push((r, c));
                 }
             }
         }
-        path
+        changed
+    }
+
+    pub fn save_zones(&self) -> std::io::Result<String> {
+        let path = zones::path(self.dijkstra.width, self.dijkstra.height);
+        zones::save(&self.network.zones, &path)?;
+        Ok(path)
     }
 
     pub fn near_station(&self, row: usize, col: usize) -> Option<(usize, usize)> {
         let mut closest = None;
         let mut closest_distance = 100;
-        for (station_row, station_col) in self.stations.iter() {
+        for (station_row, station_col) in self.network.stations.iter() {
             let distance_squared =
                 station_row.abs_diff(row).pow(2) + station_col.abs_diff(col).pow(2);
             if distance_squared < closest_distance {
@@ -271,58 +451,316 @@ impl MapState {
         closest
     }
 
+    // Height scaled to 0 at the lowest point of the map and 1 at the highest.
+    pub fn normalized_height(&self, row: usize, col: usize) -> f32 {
+        (self.dijkstra.height_map[row][col] - self.min_height) / (self.max_height - self.min_height)
+    }
+
     pub fn render_image(&self, image: &mut Image) {
         for i in 0..self.dijkstra.width {
             for j in 0..self.dijkstra.height {
                 let pixel = image
                     .pixel_bytes_mut(UVec3::new(i as u32, j as u32, 0))
                     .unwrap();
-                let level = |x: f32| (x * 30.0).floor();
-                let value = (self.dijkstra.height_map[j][i] - self.min_height)
-                    / (self.max_height - self.min_height);
-                let value_north = if j > 0 {
-                    (self.dijkstra.height_map[j - 1][i] - self.min_height)
-                        / (self.max_height - self.min_height)
-                } else {
-                    value
-                };
-                let shadow = 30.0 * (value - value_north) + 1.0;
-                let should_draw_level_lines = true;
-                let lerp = |a: i32, b: i32, t: f32, s: f32| {
-                    (shadow * s * (a as f32 + t * (b as f32 - a as f32))).clamp(0.0, 255.0) as u8
-                };
-                let rgb = |v, s| (lerp(0, 100, v, s), lerp(150, 50, v, s), lerp(0, 50, v, s));
-                if self.dijkstra.is_water[j][i] {
-                    pixel[0] = 0;
-                    pixel[1] = 0;
-                    pixel[2] = 255;
-                } else if should_draw_level_lines
-                    && i + 1 < self.dijkstra.width
-                    && j + 1 < self.dijkstra.height
-                    && (level(self.dijkstra.height_map[j][i])
-                        != level(self.dijkstra.height_map[j + 1][i])
-                        || level(self.dijkstra.height_map[j][i])
-                            != level(self.dijkstra.height_map[j][i + 1]))
-                {
-                    let (r, g, b) = rgb(value, 0.85);
-                    pixel[0] = r;
-                    pixel[1] = g;
-                    pixel[2] = b;
-                } else {
-                    // use std::f32::consts::PI;
-                    // let value = 2.0
-                    //     * PI
-                    //     * ((self.height_map[j][i] - self.min_height) / (self.max_height - self.min_height));
-                    // pixel[0] = ((value.sin() + 1.0) * 127.5) as u8;
-                    // pixel[1] = (((value + 2.0 * PI / 3.0).sin() + 1.0) * 127.5) as u8;
-                    // pixel[2] = (((value + 4.0 * PI / 3.0).sin() + 1.0) * 127.5) as u8;
-
-                    let (r, g, b) = rgb(value, 1.0);
-                    pixel[0] = r;
-                    pixel[1] = g;
-                    pixel[2] = b;
+                pixel[..3].copy_from_slice(&self.terrain_color(i, j));
+            }
+        }
+    }
+
+    fn terrain_color(&self, i: usize, j: usize) -> [u8; 3] {
+        let level = |x: f32| (x * 30.0).floor();
+        let value = (self.dijkstra.height_map[j][i] - self.min_height)
+            / (self.max_height - self.min_height);
+        let value_north = if j > 0 {
+            (self.dijkstra.height_map[j - 1][i] - self.min_height)
+                / (self.max_height - self.min_height)
+        } else {
+            value
+        };
+        let shadow = 30.0 * (value - value_north) + 1.0;
+        let should_draw_level_lines = true;
+        let lerp = |a: i32, b: i32, t: f32, s: f32| {
+            (shadow * s * (a as f32 + t * (b as f32 - a as f32))).clamp(0.0, 255.0) as u8
+        };
+        let rgb = |v, s| [lerp(0, 100, v, s), lerp(150, 50, v, s), lerp(0, 50, v, s)];
+        if self.dijkstra.is_water[j][i] {
+            [0, 0, 255]
+        } else if should_draw_level_lines
+            && i + 1 < self.dijkstra.width
+            && j + 1 < self.dijkstra.height
+            && (level(self.dijkstra.height_map[j][i]) != level(self.dijkstra.height_map[j + 1][i])
+                || level(self.dijkstra.height_map[j][i])
+                    != level(self.dijkstra.height_map[j][i + 1]))
+        {
+            rgb(value, 0.85)
+        } else {
+            // use std::f32::consts::PI;
+            // let value = 2.0
+            //     * PI
+            //     * ((self.height_map[j][i] - self.min_height) / (self.max_height - self.min_height));
+            // pixel[0] = ((value.sin() + 1.0) * 127.5) as u8;
+            // pixel[1] = (((value + 2.0 * PI / 3.0).sin() + 1.0) * 127.5) as u8;
+            // pixel[2] = (((value + 4.0 * PI / 3.0).sin() + 1.0) * 127.5) as u8;
+
+            rgb(value, 1.0)
+        }
+    }
+}
+
+fn paint(
+    image: &mut Image,
+    (row, col): (usize, usize),
+    color: [u8; 3],
+    pixels: &mut Vec<PixelChange>,
+) {
+    let pixel = image
+        .pixel_bytes_mut(UVec3::new(col as u32, row as u32, 0))
+        .unwrap();
+    pixels.push(((row, col), [pixel[0], pixel[1], pixel[2]], color));
+    pixel[..3].copy_from_slice(&color);
+}
+
+// Fills the basins of the ground with lakes and traces the rivers they
+// overflow into, returning the heights with lakes levelled and which cells
+// are water. With `flat_drains`, level ground joins the cells around it
+// instead of every cell of it becoming a lake of its own, which reshaped
+// ground needs; the generated map keeps its original lakes.
+#[allow(clippy::needless_range_loop)]
+pub fn extract_water(ground: &[Vec<f32>], flat_drains: bool) -> (Vec<Vec<f32>>, Vec<Vec<bool>>) {
+    let (height, width) = (ground.len(), ground[0].len());
+    let mut height_map = ground.to_vec();
+    let mut points_with_height = Vec::new();
+    let mut lake_id: Vec<Vec<usize>> = vec![vec![0; width]; height];
+    let mut actual_lake_id = HashMap::new();
+    let mut overflown_lakes = HashSet::new();
+    actual_lake_id.insert(0, 0);
+    let mut lake_level = HashMap::new();
+    let mut next_lake_id = 1;
+    let mut is_water = vec![vec![false; width]; height];
+    // Cells handled so far, lower than the current one or level with it.
+    let mut visited = vec![vec![false; width]; height];
+    for col in 0..width {
+        for row in 0..height {
+            points_with_height.push((height_map[row][col], row, col));
+        }
+    }
+    points_with_height.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let is_edge =
+        |row: usize, col: usize| row == 0 || col == 0 || row == height - 1 || col == width - 1;
+    // let mut points_handled = 0;
+    for (h, row, col) in points_with_height {
+        // points_handled += 1;
+        let neighbor_lake_ids = [
+            (row.saturating_sub(1), col),
+            ((row + 1).min(height - 1), col),
+            (row, col.saturating_sub(1)),
+            (row, (col + 1).min(width - 1)),
+        ]
+        .iter()
+        .filter(|&(r, c)| {
+            if flat_drains {
+                visited[*r][*c]
+            } else {
+                height_map[*r][*c] < h
+            }
+        })
+        .map(|&(r, c)| actual_lake_id[&lake_id[r][c]])
+        .collect::<HashSet<_>>();
+        visited[row][col] = true;
+        if is_edge(row, col) {
+            lake_id[row][col] = 0;
+            for lake_id in neighbor_lake_ids.iter() {
+                if *lake_id == 0 {
+                    continue;
                 }
+                overflown_lakes.insert(*lake_id);
             }
+            continue;
+        }
+        if neighbor_lake_ids.is_empty() {
+            lake_id[row][col] = next_lake_id;
+            is_water[row][col] = true;
+            // println!("Lakes: {}", next_lake_id);
+            actual_lake_id.insert(next_lake_id, next_lake_id);
+            lake_level.insert(next_lake_id, h);
+            next_lake_id += 1;
+            continue;
         }
+        if neighbor_lake_ids.contains(&0) {
+            let mut river = false;
+            for other_lake_id in neighbor_lake_ids.iter() {
+                if *other_lake_id == 0 {
+                    continue;
+                }
+                if overflown_lakes.contains(other_lake_id) {
+                    continue;
+                }
+                overflown_lakes.insert(*other_lake_id);
+                river = true;
+            }
+            if river {
+                let mut r = row;
+                let mut c = col;
+                while !is_edge(r, c) {
+                    is_water[r][c] = true;
+                    // let neighbors = [
+                    //     (r.saturating_sub(1), c),
+                    //     ((r + 1).min(height - 1), c),
+                    //     (r, c.saturating_sub(1)),
+                    //     (r, (c + 1).min(width - 1)),
+                    // ];
+                    let mut neighbors = Vec::new();
+                    for dr in -1..=1 {
+                        for dc in -1..=1 {
+                            neighbors
+                                .push(((r as isize + dr) as usize, (c as isize + dc) as usize));
+                        }
+                    }
+                    let non_same_lake_lower_neighbors = neighbors
+                        .iter()
+                        .filter(|&&(nr, nc)| {
+                            height_map[nr][nc] < height_map[r][c]
+                                && (actual_lake_id[&lake_id[nr][nc]] == 0
+                                    || !neighbor_lake_ids
+                                        .contains(&actual_lake_id[&lake_id[nr][nc]]))
+                        })
+                        .collect::<Vec<_>>();
+                    if non_same_lake_lower_neighbors.is_empty() {
+                        break;
+                    }
+                    let lowest_neighbor = non_same_lake_lower_neighbors
+                        .iter()
+                        .map(|&&(nr, nc)| {
+                            (
+                                (height_map[nr][nc] - height_map[r][c])
+                                    / ((nr as f32 - r as f32) * (nr as f32 - r as f32)
+                                        + (nc as f32 - c as f32) * (nc as f32 - c as f32))
+                                        .sqrt(),
+                                nr,
+                                nc,
+                            )
+                        })
+                        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+                        .unwrap();
+                    r = lowest_neighbor.1;
+                    c = lowest_neighbor.2;
+                }
+            }
+            lake_id[row][col] = 0;
+            continue;
+        }
+        let non_overflow_neighbor_lake_ids = neighbor_lake_ids
+            .iter()
+            .filter(|&&x| !overflown_lakes.contains(&x))
+            .cloned()
+            .collect::<HashSet<_>>();
+        if non_overflow_neighbor_lake_ids.is_empty() {
+            lake_id[row][col] = 0;
+            continue;
+        }
+        // This point is part of one ore more lakes.
+        let smallest_lake_id = non_overflow_neighbor_lake_ids
+            .iter()
+            .cloned()
+            .min()
+            .unwrap();
+        lake_id[row][col] = smallest_lake_id;
+        is_water[row][col] = true;
+        lake_level.insert(smallest_lake_id, h);
+        for other_lake_id in non_overflow_neighbor_lake_ids {
+            if other_lake_id == smallest_lake_id {
+                continue;
+            }
+            actual_lake_id.insert(other_lake_id, smallest_lake_id);
+        }
+    }
+
+    println!("Lakes found: {}", next_lake_id);
+
+    for row in 0..height {
+        for col in 0..width {
+            if lake_id[row][col] != 0 {
+                height_map[row][col] = lake_level[&actual_lake_id[&lake_id[row][col]]];
+            }
+        }
+    }
+
+    (height_map, is_water)
+}
+
+fn height_range(height_map: &[Vec<f32>]) -> (f32, f32) {
+    let heights = height_map.iter().flatten().cloned();
+    (
+        heights.clone().reduce(f32::min).unwrap(),
+        heights.reduce(f32::max).unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::Structure;
+    use bevy::asset::RenderAssetUsages;
+    use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+    // A level plateau one cell in from the edges of a 5 by 5 map.
+    fn plateau() -> Vec<Vec<f32>> {
+        (0..5)
+            .map(|row| {
+                (0..5)
+                    .map(|col| {
+                        if (1..4).contains(&row) && (1..4).contains(&col) {
+                            0.5
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn generated_level_ground_holds_a_lake() {
+        let (_, is_water) = extract_water(&plateau(), false);
+        assert!(is_water[2][2]);
+    }
+
+    #[test]
+    fn reshaped_level_ground_drains() {
+        let (_, is_water) = extract_water(&plateau(), true);
+        assert!(is_water.iter().flatten().all(|&water| !water));
+    }
+
+    #[test]
+    fn flooded_track_is_measured_as_a_bridge() {
+        let mut map_state = MapState::from_heights(vec![vec![0.0; 16]; 16]);
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: 16,
+                height: 16,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0, 0, 0, 255],
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::MAIN_WORLD,
+        );
+        let plan = RoutePlan::straight(Mode::Rail, (4, 2), (4, 12));
+        map_state.commit(&plan, &mut image);
+        let edge = map_state.graph.edges.values().next().unwrap();
+        assert_eq!(edge.structure(), Structure::Track);
+        let mut is_water = vec![vec![false; 16]; 16];
+        for cell in is_water[4][3..12].iter_mut() {
+            *cell = true;
+        }
+        let water = Water {
+            id: 1,
+            height_map: vec![vec![0.0; 16]; 16],
+            is_water,
+        };
+        map_state.set_water(water, &mut image);
+        let edge = map_state.graph.edges.values().next().unwrap();
+        assert_eq!(edge.structure(), Structure::Bridge);
     }
 }
@@ -0,0 +1,309 @@
+use crate::dijkstra::Dijkstra;
+use crate::network::Network;
+use crate::zones::{ZONE_PENALTY, Zone};
+
+// How much each factor counts towards the suitability of a cell.
+const FLATNESS_WEIGHT: f32 = 0.3;
+const WATER_WEIGHT: f32 = 0.2;
+const ALTITUDE_WEIGHT: f32 = 0.15;
+const ACCESS_WEIGHT: f32 = 0.2;
+const BIOME_WEIGHT: f32 = 0.15;
+// Cells over which the pull of water and of the network fades.
+const WATER_RANGE: f32 = 15.0;
+const ACCESS_RANGE: f32 = 30.0;
+
+// What the land is like, from its altitude and how close water is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Biome {
+    Water,
+    Wetland,
+    Grassland,
+    Forest,
+    Highland,
+    Mountain,
+}
+
+impl Biome {
+    fn suitability(self) -> f32 {
+        match self {
+            Biome::Water => 0.0,
+            Biome::Wetland => 0.4,
+            Biome::Grassland => 1.0,
+            Biome::Forest => 0.6,
+            Biome::Highland => 0.3,
+            Biome::Mountain => 0.0,
+        }
+    }
+}
+
+// Each factor of a cell's suitability, from 0 for the worst to 1 for the
+// best, and the biome behind the last of them.
+#[derive(Clone, Copy, Debug)]
+pub struct Factors {
+    pub flatness: f32,
+    pub water: f32,
+    pub altitude: f32,
+    pub access: f32,
+    pub biome: Biome,
+}
+
+impl Factors {
+    pub fn score(&self) -> f32 {
+        if self.biome == Biome::Water {
+            return 0.0;
+        }
+        FLATNESS_WEIGHT * self.flatness
+            + WATER_WEIGHT * self.water
+            + ALTITUDE_WEIGHT * self.altitude
+            + ACCESS_WEIGHT * self.access
+            + BIOME_WEIGHT * self.biome.suitability()
+    }
+}
+
+// How well every cell of the map suits a town or a station, for the network
+// as it was when the map was computed.
+pub struct Suitability {
+    pub width: usize,
+    pub height: usize,
+    pub factors: Vec<Vec<Factors>>,
+    pub scores: Vec<Vec<f32>>,
+}
+
+impl Suitability {
+    pub fn compute(dijkstra: &Dijkstra, network: &Network) -> Self {
+        let (width, height) = (dijkstra.width, dijkstra.height);
+        let heights = dijkstra.height_map.iter().flatten();
+        let min_height = heights.clone().cloned().fold(f32::MAX, f32::min);
+        let max_height = heights.cloned().fold(f32::MIN, f32::max);
+        let water_distance = distance_field(width, height, |row, col| dijkstra.is_water[row][col]);
+        let access_distance = distance_field(width, height, |row, col| {
+            network.rail_level[row][col] != 0
+                || network.road_level[row][col] != 0
+                || network.stations.contains(&(row, col))
+        });
+        let mut factors = Vec::with_capacity(height);
+        for row in 0..height {
+            let mut factor_row = Vec::with_capacity(width);
+            for col in 0..width {
+                let altitude =
+                    (dijkstra.height_map[row][col] - min_height) / (max_height - min_height);
+                let water = water_distance[row][col];
+                factor_row.push(Factors {
+                    flatness: flatness(dijkstra, (row, col)),
+                    water: if water == 0.0 {
+                        0.0
+                    } else {
+                        (-water / WATER_RANGE).exp()
+                    },
+                    altitude: 1.0 - altitude,
+                    access: (-access_distance[row][col] / ACCESS_RANGE).exp(),
+                    biome: biome(altitude, water),
+                });
+            }
+            factors.push(factor_row);
+        }
+        let scores = factors
+            .iter()
+            .enumerate()
+            .map(|(row, factor_row)| {
+                factor_row
+                    .iter()
+                    .enumerate()
+                    .map(|(col, factors)| {
+                        if network.house_level[row][col] != 0 {
+                            return 0.0;
+                        }
+                        match network.zones[row][col] {
+                            Zone::Free => factors.score(),
+                            Zone::Penalty => factors.score() / ZONE_PENALTY,
+                            Zone::Forbidden => 0.0,
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Suitability {
+            width,
+            height,
+            factors,
+            scores,
+        }
+    }
+
+    pub fn score(&self, (row, col): (usize, usize)) -> f32 {
+        self.scores[row][col]
+    }
+
+    // The `count` best cells at least `spacing` apart from each other and
+    // from every cell in `avoid`, best first.
+    pub fn best_sites(
+        &self,
+        count: usize,
+        spacing: usize,
+        avoid: &[(usize, usize)],
+    ) -> Vec<(usize, usize)> {
+        let mut cells = Vec::new();
+        for row in 0..self.height {
+            for col in 0..self.width {
+                if self.scores[row][col] > 0.0 {
+                    cells.push((row, col));
+                }
+            }
+        }
+        cells.sort_by(|&a, &b| self.score(b).total_cmp(&self.score(a)));
+        let far = |a: (usize, usize), b: &(usize, usize)| {
+            a.0.abs_diff(b.0).pow(2) + a.1.abs_diff(b.1).pow(2) >= spacing.pow(2)
+        };
+        let mut sites = Vec::new();
+        for cell in cells {
+            if sites.len() == count {
+                break;
+            }
+            if avoid.iter().all(|other| far(cell, other))
+                && sites.iter().all(|other| far(cell, other))
+            {
+                sites.push(cell);
+            }
+        }
+        sites
+    }
+}
+
+// 1 on level ground, falling to 0 where the grades across and along the
+// cell add up to 10%.
+pub fn flatness(dijkstra: &Dijkstra, (row, col): (usize, usize)) -> f32 {
+    let height = dijkstra.height_map[row][col];
+    let next_row = (row + 1).min(dijkstra.height - 1);
+    let next_col = (col + 1).min(dijkstra.width - 1);
+    let grade = dijkstra
+        .scale
+        .grade((dijkstra.height_map[next_row][col] - height).abs(), 1.0)
+        + dijkstra
+            .scale
+            .grade((dijkstra.height_map[row][next_col] - height).abs(), 1.0);
+    (1.0 - grade / 10.0).clamp(0.0, 1.0)
+}
+
+fn biome(altitude: f32, water_distance: f32) -> Biome {
+    match (altitude, water_distance) {
+        (_, 0.0) => Biome::Water,
+        (..0.4, ..3.0) => Biome::Wetland,
+        (..0.4, _) => Biome::Grassland,
+        (..0.6, _) => Biome::Forest,
+        (..0.8, _) => Biome::Highland,
+        _ => Biome::Mountain,
+    }
+}
+
+// Distance from every cell to the nearest cell where `source` holds, in two
+// chamfer passes. Infinite everywhere if there is no such cell.
+#[allow(clippy::needless_range_loop)]
+fn distance_field(
+    width: usize,
+    height: usize,
+    source: impl Fn(usize, usize) -> bool,
+) -> Vec<Vec<f32>> {
+    let diagonal = std::f32::consts::SQRT_2;
+    let mut distance = vec![vec![f32::INFINITY; width]; height];
+    for row in 0..height {
+        for col in 0..width {
+            if source(row, col) {
+                distance[row][col] = 0.0;
+            }
+        }
+    }
+    for row in 0..height {
+        for col in 0..width {
+            let mut best = distance[row][col];
+            if row > 0 {
+                best = best.min(distance[row - 1][col] + 1.0);
+                if col > 0 {
+                    best = best.min(distance[row - 1][col - 1] + diagonal);
+                }
+                if col + 1 < width {
+                    best = best.min(distance[row - 1][col + 1] + diagonal);
+                }
+            }
+            if col > 0 {
+                best = best.min(distance[row][col - 1] + 1.0);
+            }
+            distance[row][col] = best;
+        }
+    }
+    for row in (0..height).rev() {
+        for col in (0..width).rev() {
+            let mut best = distance[row][col];
+            if row + 1 < height {
+                best = best.min(distance[row + 1][col] + 1.0);
+                if col > 0 {
+                    best = best.min(distance[row + 1][col - 1] + diagonal);
+                }
+                if col + 1 < width {
+                    best = best.min(distance[row + 1][col + 1] + diagonal);
+                }
+            }
+            if col + 1 < width {
+                best = best.min(distance[row][col + 1] + 1.0);
+            }
+            distance[row][col] = best;
+        }
+    }
+    distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suitability(scores: Vec<Vec<f32>>) -> Suitability {
+        let (height, width) = (scores.len(), scores[0].len());
+        let factors = Factors {
+            flatness: 0.0,
+            water: 0.0,
+            altitude: 0.0,
+            access: 0.0,
+            biome: Biome::Grassland,
+        };
+        Suitability {
+            width,
+            height,
+            factors: vec![vec![factors; width]; height],
+            scores,
+        }
+    }
+
+    #[test]
+    fn best_sites_are_spaced_apart_best_first() {
+        let mut scores = vec![vec![0.1; 10]; 10];
+        scores[1][1] = 0.9;
+        scores[1][2] = 0.8;
+        scores[8][8] = 0.7;
+        scores[5][5] = 0.0;
+        let map = suitability(scores);
+        assert_eq!(map.best_sites(2, 3, &[]), vec![(1, 1), (8, 8)]);
+        assert_eq!(map.best_sites(1, 3, &[(0, 0)]), vec![(8, 8)]);
+    }
+
+    #[test]
+    fn best_sites_skip_unsuitable_cells() {
+        let mut scores = vec![vec![0.0; 4]; 4];
+        scores[2][3] = 0.5;
+        assert_eq!(suitability(scores).best_sites(3, 1, &[]), vec![(2, 3)]);
+    }
+
+    #[test]
+    fn distance_field_measures_to_the_nearest_source() {
+        let distance = distance_field(5, 5, |row, col| (row, col) == (2, 2));
+        let diagonal = std::f32::consts::SQRT_2;
+        assert_eq!(distance[2][2], 0.0);
+        assert_eq!(distance[2][4], 2.0);
+        assert_eq!(distance[0][0], 2.0 * diagonal);
+        assert_eq!(distance[4][3], diagonal + 1.0);
+    }
+
+    #[test]
+    fn distance_field_without_sources_is_infinite() {
+        let distance = distance_field(3, 3, |_, _| false);
+        assert!(distance.iter().flatten().all(|d| d.is_infinite()));
+    }
+}
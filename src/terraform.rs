@@ -0,0 +1,224 @@
+use bevy::prelude::*;
+
+// Brush radius in cells and strength in height per second to start with,
+// and their limits.
+const DEFAULT_RADIUS: usize = 10;
+const MIN_RADIUS: usize = 2;
+const MAX_RADIUS: usize = 64;
+const DEFAULT_STRENGTH: f32 = 0.02;
+const MIN_STRENGTH: f32 = 0.0025;
+const MAX_STRENGTH: f32 = 0.32;
+// How far below the lowest point of its rim a dug lake's bed is.
+const LAKE_DEPTH: f32 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Raise,
+    Lower,
+    // Levels the land to the height where the stroke started.
+    Flatten,
+    Smooth,
+    // Digs a flat-bottomed pit below its rim for water to fill.
+    DigLake,
+}
+
+impl Tool {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Raise => "raise",
+            Tool::Lower => "lower",
+            Tool::Flatten => "flatten",
+            Tool::Smooth => "smooth",
+            Tool::DigLake => "dig lake",
+        }
+    }
+
+    // The tool after this one, or none after the last.
+    pub fn next(tool: Option<Tool>) -> Option<Tool> {
+        match tool {
+            None => Some(Tool::Raise),
+            Some(Tool::Raise) => Some(Tool::Lower),
+            Some(Tool::Lower) => Some(Tool::Flatten),
+            Some(Tool::Flatten) => Some(Tool::Smooth),
+            Some(Tool::Smooth) => Some(Tool::DigLake),
+            Some(Tool::DigLake) => None,
+        }
+    }
+}
+
+// The terrain brush: which tool it is, how big and strong, the level the
+// current stroke works towards, and whether the stroke changed anything.
+#[derive(Resource)]
+pub struct Terraform {
+    pub tool: Option<Tool>,
+    pub radius: usize,
+    pub strength: f32,
+    level: Option<f32>,
+    touched: bool,
+}
+
+impl Default for Terraform {
+    fn default() -> Self {
+        Terraform {
+            tool: None,
+            radius: DEFAULT_RADIUS,
+            strength: DEFAULT_STRENGTH,
+            level: None,
+            touched: false,
+        }
+    }
+}
+
+impl Terraform {
+    pub fn resize(&mut self, grow: bool) {
+        self.radius = if grow {
+            (self.radius + 2).min(MAX_RADIUS)
+        } else {
+            self.radius.saturating_sub(2).max(MIN_RADIUS)
+        };
+    }
+
+    pub fn strengthen(&mut self, stronger: bool) {
+        self.strength = if stronger {
+            (self.strength * 2.0).min(MAX_STRENGTH)
+        } else {
+            (self.strength / 2.0).max(MIN_STRENGTH)
+        };
+    }
+
+    // Works the ground around the centre for the given time, most strongly
+    // in the middle, and returns the cells it changed.
+    #[allow(clippy::needless_range_loop)]
+    pub fn brush(
+        &mut self,
+        ground: &mut [Vec<f32>],
+        (row, col): (usize, usize),
+        seconds: f32,
+    ) -> Vec<(usize, usize)> {
+        let Some(tool) = self.tool else {
+            return Vec::new();
+        };
+        let (height, width) = (ground.len(), ground[0].len());
+        let radius = self.radius;
+        let mut cells = Vec::new();
+        for r in row.saturating_sub(radius)..(row + radius + 1).min(height) {
+            for c in col.saturating_sub(radius)..(col + radius + 1).min(width) {
+                let distance_squared = r.abs_diff(row).pow(2) + c.abs_diff(col).pow(2);
+                if distance_squared <= radius.pow(2) {
+                    cells.push(((r, c), distance_squared as f32));
+                }
+            }
+        }
+        let level = *self.level.get_or_insert_with(|| match tool {
+            Tool::DigLake => {
+                cells
+                    .iter()
+                    .filter(|&&(_, distance_squared)| {
+                        distance_squared > ((radius - 1).pow(2)) as f32
+                    })
+                    .map(|&((r, c), _)| ground[r][c])
+                    .fold(f32::MAX, f32::min)
+                    - LAKE_DEPTH
+            }
+            _ => ground[row][col],
+        });
+        let before = cells
+            .iter()
+            .map(|&((r, c), _)| match tool {
+                Tool::Smooth => {
+                    let mut sum = 0.0;
+                    let mut count = 0;
+                    for nr in r.saturating_sub(1)..(r + 2).min(height) {
+                        for nc in c.saturating_sub(1)..(c + 2).min(width) {
+                            sum += ground[nr][nc];
+                            count += 1;
+                        }
+                    }
+                    sum / count as f32
+                }
+                _ => ground[r][c],
+            })
+            .collect::<Vec<_>>();
+        let mut changed = Vec::new();
+        for (&((r, c), distance_squared), target) in cells.iter().zip(before) {
+            let falloff = (1.0 - distance_squared / (radius * radius) as f32).powi(2);
+            let step = self.strength * seconds * falloff;
+            let current = ground[r][c];
+            let next = match tool {
+                Tool::Raise => current + step,
+                Tool::Lower => current - step,
+                Tool::Flatten => current + (level - current).clamp(-step, step),
+                Tool::Smooth => current + (target - current).clamp(-step, step),
+                Tool::DigLake => current - (current - level).clamp(0.0, step),
+            };
+            if next != current {
+                ground[r][c] = next;
+                changed.push((r, c));
+            }
+        }
+        self.touched |= !changed.is_empty();
+        changed
+    }
+
+    // Ends the stroke, returning whether it changed the ground.
+    pub fn finish(&mut self) -> bool {
+        self.level = None;
+        std::mem::take(&mut self.touched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brush(tool: Tool) -> Terraform {
+        Terraform {
+            tool: Some(tool),
+            radius: 2,
+            strength: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn raise_is_strongest_in_the_middle() {
+        let mut ground = vec![vec![0.0; 9]; 9];
+        let mut terraform = brush(Tool::Raise);
+        let changed = terraform.brush(&mut ground, (4, 4), 1.0);
+        assert_eq!(changed.len(), 9);
+        assert_eq!(ground[4][4], 1.0);
+        assert!(ground[4][5] > ground[4][6]);
+        assert_eq!(ground[4][6], 0.0);
+        assert!(terraform.finish());
+        assert!(!terraform.finish());
+    }
+
+    #[test]
+    fn flatten_works_towards_where_the_stroke_started() {
+        let mut ground = vec![vec![0.0; 9]; 9];
+        ground[4][4] = 0.4;
+        let mut terraform = brush(Tool::Flatten);
+        terraform.brush(&mut ground, (4, 4), 1.0);
+        ground[4][6] = 1.0;
+        terraform.brush(&mut ground, (4, 5), 10.0);
+        assert_eq!(ground[4][5], 0.4);
+        assert!((ground[4][6] - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn dig_lake_stops_below_the_rim() {
+        let mut ground = vec![vec![1.0; 9]; 9];
+        let mut terraform = brush(Tool::DigLake);
+        terraform.brush(&mut ground, (4, 4), 100.0);
+        assert_eq!(ground[4][4], 1.0 - LAKE_DEPTH);
+        assert_eq!(ground[4][5], 1.0 - LAKE_DEPTH);
+    }
+
+    #[test]
+    fn no_tool_changes_nothing() {
+        let mut ground = vec![vec![0.0; 9]; 9];
+        let mut terraform = Terraform::default();
+        assert!(terraform.brush(&mut ground, (4, 4), 1.0).is_empty());
+        assert!(!terraform.finish());
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::needless_range_loop)]
 pub fn height_map(w: usize, h: usize) -> Vec<Vec<f32>> {
     let mut height_map = vec![vec![0.0; w]; h];
     let perlin = Perlin {
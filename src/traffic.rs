@@ -0,0 +1,173 @@
+use crate::network::{Mode, Tier};
+use crate::state::MapState;
+use crate::train::Train;
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+// Seconds over which vehicles entering a cell are counted, so flows are in
+// vehicles per minute.
+pub const TRAFFIC_WINDOW: f32 = 60.0;
+// Seconds between looking for infrastructure that needs upgrading.
+pub const SUGGEST_INTERVAL: f32 = 10.0;
+
+// Vehicles per minute a cell of each tier carries before it is congested.
+pub fn capacity(mode: Mode, tier: Tier) -> f32 {
+    match (mode, tier) {
+        (Mode::Rail, Tier::Basic) => 4.0,
+        (Mode::Rail, Tier::Improved) => 12.0,
+        (Mode::Rail, Tier::Major) => 20.0,
+        (Mode::Road, Tier::Basic) => 8.0,
+        (Mode::Road, Tier::Improved) => 24.0,
+        (Mode::Road, Tier::Major) => 60.0,
+    }
+}
+
+// A stretch of the network carrying more than its tier allows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upgrade {
+    pub mode: Mode,
+    pub cells: Vec<(usize, usize)>,
+    pub tier: Tier,
+    pub flow: f32,
+}
+
+// Vehicles entering each cell recently, and the upgrades that would relieve
+// congestion.
+#[derive(Resource, Default)]
+pub struct Traffic {
+    pub flow: HashMap<(Mode, (usize, usize)), f32>,
+    pub suggestions: Vec<Upgrade>,
+    last_cell: HashMap<Entity, (usize, usize)>,
+    since_check: f32,
+}
+
+impl Traffic {
+    // Counts the vehicles that moved into a new cell, letting older counts
+    // fade over the traffic window.
+    pub fn measure(&mut self, trains: &[(Entity, &Train)], seconds: f32) {
+        let decay = (-seconds / TRAFFIC_WINDOW).exp();
+        self.flow.retain(|_, flow| {
+            *flow *= decay;
+            *flow > 0.01
+        });
+        self.last_cell
+            .retain(|entity, _| trains.iter().any(|(other, _)| other == entity));
+        for &(entity, train) in trains.iter() {
+            let cell = train.path[train.behind()];
+            if self.last_cell.insert(entity, cell) != Some(cell) {
+                *self.flow.entry((train.mode, cell)).or_default() += 1.0;
+            }
+        }
+        self.since_check += seconds;
+    }
+
+    // Every few seconds, lists the edges of both networks whose busiest cell
+    // carries more than the lowest tier on them allows, most congested first.
+    // Returns whether the list changed.
+    pub fn suggest(&mut self, map_state: &MapState) -> bool {
+        if self.since_check < SUGGEST_INTERVAL {
+            return false;
+        }
+        self.since_check = 0.0;
+        let mut suggestions = Vec::new();
+        for mode in [Mode::Rail, Mode::Road] {
+            let tiers = map_state.network.tier(mode);
+            for edge in map_state.graph(mode).edges.values() {
+                let flow = edge
+                    .cells
+                    .iter()
+                    .filter_map(|&cell| self.flow.get(&(mode, cell)))
+                    .fold(0.0, |a: f32, &b| a.max(b));
+                let tier = edge
+                    .cells
+                    .iter()
+                    .map(|&(row, col)| tiers[row][col])
+                    .min()
+                    .unwrap_or_default();
+                if tier.next().is_some() && flow > capacity(mode, tier) {
+                    suggestions.push(Upgrade {
+                        mode,
+                        cells: edge.cells.clone(),
+                        tier,
+                        flow,
+                    });
+                }
+            }
+        }
+        suggestions.sort_by(|a, b| {
+            let load = |upgrade: &Upgrade| upgrade.flow / capacity(upgrade.mode, upgrade.tier);
+            load(b).total_cmp(&load(a))
+        });
+        let key = |suggestions: &[Upgrade]| {
+            suggestions
+                .iter()
+                .map(|upgrade| (upgrade.mode, upgrade.tier, upgrade.cells[0]))
+                .collect::<Vec<_>>()
+        };
+        let changed = key(&suggestions) != key(&self.suggestions);
+        self.suggestions = suggestions;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::RoutePlan;
+    use std::sync::Arc;
+
+    // A single railway from (4, 2) to (4, 12).
+    fn map_state() -> MapState {
+        let mut map_state = MapState::from_heights(vec![vec![0.0; 16]; 16]);
+        let plan = RoutePlan::straight(Mode::Rail, (4, 2), (4, 12));
+        let network = Arc::make_mut(&mut map_state.network);
+        network.commit(&plan);
+        map_state.graph.add_route(&plan);
+        map_state
+            .graph
+            .refresh(plan.cells(), &network.stations, &map_state.dijkstra);
+        map_state
+    }
+
+    fn congested() -> Traffic {
+        let mut traffic = Traffic {
+            since_check: SUGGEST_INTERVAL,
+            ..Default::default()
+        };
+        traffic.flow.insert((Mode::Rail, (4, 7)), 5.0);
+        traffic
+    }
+
+    #[test]
+    fn suggest_upgrades_congested_edges() {
+        let map_state = map_state();
+        let mut traffic = congested();
+        assert!(traffic.suggest(&map_state));
+        assert_eq!(traffic.suggestions.len(), 1);
+        assert_eq!(traffic.suggestions[0].tier, Tier::Basic);
+        assert_eq!(traffic.suggestions[0].flow, 5.0);
+        assert_eq!(traffic.suggestions[0].cells.len(), 11);
+    }
+
+    #[test]
+    fn suggest_waits_for_its_interval() {
+        let map_state = map_state();
+        let mut traffic = congested();
+        traffic.since_check = 0.0;
+        assert!(!traffic.suggest(&map_state));
+        assert!(traffic.suggestions.is_empty());
+    }
+
+    #[test]
+    fn suggest_leaves_the_top_tier_alone() {
+        let mut map_state = map_state();
+        let cells = map_state.graph.edges.values().next().unwrap().cells.clone();
+        let network = Arc::make_mut(&mut map_state.network);
+        network.upgrade(Mode::Rail, &cells);
+        network.upgrade(Mode::Rail, &cells);
+        let mut traffic = congested();
+        traffic.flow.insert((Mode::Rail, (4, 7)), 100.0);
+        traffic.suggest(&map_state);
+        assert!(traffic.suggestions.is_empty());
+    }
+}
@@ -1,18 +1,460 @@
+use crate::demand::{Cargo, Load};
+use crate::line::LineId;
+use crate::network::Mode;
+use crate::scale::Scale;
 use bevy::prelude::*;
 
+// Cells per second.
+pub const DEFAULT_MAX_SPEED: f32 = 40.0;
+// Tonnes and kilonewtons, for the default train, so that their ratio is in
+// metres per second squared.
+pub const DEFAULT_MASS: f32 = 400.0;
+pub const DEFAULT_TRACTIVE_EFFORT: f32 = 3200.0;
+// A loaded freight train, heavy enough to slow to a crawl on steep grades.
+pub const FREIGHT_MASS: f32 = 4000.0;
+// Metres per second squared.
+pub const DEFAULT_BRAKING: f32 = 10.0;
+pub const GRAVITY: f32 = 9.81;
+// Rolling resistance as a fraction of the train's weight.
+pub const DEFAULT_ROLLING_RESISTANCE: f32 = 0.002;
+// However steep the way down, the brakes still take off at least this
+// fraction of their strength.
+const MIN_NET_BRAKING: f32 = 0.25;
+// Trains too weak for a grade still creep up it at this speed.
+pub const CRAWL_SPEED: f32 = 1.0;
+// Seconds a train stands at a station by default.
+pub const STATION_DWELL: f32 = 2.0;
+// Wagons behind the locomotive, and the size and spacing of each car in
+// cells.
+pub const DEFAULT_WAGONS: usize = 3;
+pub const CAR_LENGTH: f32 = 4.0;
+pub const CAR_WIDTH: f32 = 2.0;
+pub const CAR_SPACING: f32 = 5.0;
+// Buses and trucks: slower and far lighter than trains, with no wagons.
+pub const ROAD_MAX_SPEED: f32 = 25.0;
+pub const ROAD_MASS: f32 = 15.0;
+pub const ROAD_TRACTIVE_EFFORT: f32 = 150.0;
+pub const ROAD_BRAKING: f32 = 15.0;
+pub const ROAD_ROLLING_RESISTANCE: f32 = 0.01;
+// Cells either side of the train the grade is averaged over.
+const GRADE_WINDOW: usize = 4;
+
+// One car of a train, numbered from the locomotive at 0.
 #[derive(Component)]
+pub struct Car(pub usize);
+
+// Names a train across undo and redo, which despawn it and spawn it again
+// as a new entity.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TrainId(pub u64);
+
+#[derive(Component, Clone)]
 pub struct Train {
+    pub mode: Mode,
     pub path: Vec<(usize, usize)>,
-    pub index: usize,
+    // Distance along the path from its first cell to each of its cells.
+    pub offsets: Vec<f32>,
+    // Distance travelled from the first cell, and where it was before the
+    // last step so it can be drawn between steps.
+    pub distance: f32,
+    pub previous_distance: f32,
     pub forward: bool,
+    pub speed: f32,
+    pub max_speed: f32,
+    pub mass: f32,
+    // Pulling force at standstill, falling to nothing at top speed.
+    pub tractive_effort: f32,
+    pub braking: f32,
+    pub rolling_resistance: f32,
+    // Cells it needs to stop from its speed on the grade under it, as of the
+    // last step.
+    pub stopping_distance: f32,
+    // Seconds left standing at a station, and how long each stop takes.
+    pub dwell: f32,
+    pub dwell_time: f32,
+    // Path indices of the stations the train calls at between its ends.
+    pub stops: Vec<usize>,
+    pub line: Option<LineId>,
+    // Waiting at the start of its line for the next departure slot.
+    pub held: bool,
+    // The station it just pulled in at, until it has loaded there.
+    pub arrived: Option<(usize, usize)>,
+    pub cargo: Cargo,
+    pub wagons: usize,
+    // How much it carries to each station.
+    pub load: Vec<Load>,
+    // How much further the train may go before a signal at danger.
+    pub signal_gap: Option<f32>,
 }
 
 impl Train {
     pub fn new(path: Vec<(usize, usize)>) -> Self {
         Train {
+            mode: Mode::Rail,
+            offsets: offsets(&path),
+            // Load at the first station before setting off.
+            arrived: Some(path[0]),
             path,
-            index: 0,
+            distance: 0.0,
+            previous_distance: 0.0,
             forward: true,
+            speed: 0.0,
+            max_speed: DEFAULT_MAX_SPEED,
+            mass: DEFAULT_MASS,
+            tractive_effort: DEFAULT_TRACTIVE_EFFORT,
+            braking: DEFAULT_BRAKING,
+            rolling_resistance: DEFAULT_ROLLING_RESISTANCE,
+            stopping_distance: 0.0,
+            dwell: 0.0,
+            dwell_time: STATION_DWELL,
+            stops: Vec::new(),
+            line: None,
+            held: false,
+            cargo: Cargo::Passengers,
+            wagons: DEFAULT_WAGONS,
+            load: Vec::new(),
+            signal_gap: None,
+        }
+    }
+
+    // A bus, or a truck for freight, running on roads.
+    pub fn road(path: Vec<(usize, usize)>, cargo: Cargo) -> Self {
+        Train {
+            mode: Mode::Road,
+            max_speed: ROAD_MAX_SPEED,
+            mass: ROAD_MASS,
+            tractive_effort: ROAD_TRACTIVE_EFFORT,
+            braking: ROAD_BRAKING,
+            rolling_resistance: ROAD_ROLLING_RESISTANCE,
+            cargo,
+            wagons: 0,
+            ..Train::new(path)
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.offsets[self.offsets.len() - 1]
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.held || self.dwell > 0.0
+    }
+
+    // The stations the train calls at, in path order.
+    pub fn calls(&self) -> Vec<(usize, usize)> {
+        let mut calls = vec![self.path[0]];
+        calls.extend(self.stops.iter().map(|&index| self.path[index]));
+        calls.push(self.path[self.path.len() - 1]);
+        calls
+    }
+
+    // Where along the path the train next calls: a stop, or the end it is
+    // heading to.
+    pub fn next_stop(&self) -> f32 {
+        let stops = self.stops.iter().map(|&index| self.offsets[index]);
+        if self.forward {
+            stops
+                .filter(|&offset| offset > self.distance)
+                .fold(self.length(), f32::min)
+        } else {
+            stops
+                .filter(|&offset| offset < self.distance)
+                .fold(0.0, f32::max)
+        }
+    }
+
+    // Runs the train for `seconds`: it pulls away, feels the grade under it,
+    // and brakes to call at each stop. Forces are worked out in metres per
+    // second squared and moved onto the path by the scale. At either end it turns back, or at the
+    // start of a line waits to be sent off again. `speed_factor` raises its
+    // top speed on upgraded infrastructure.
+    pub fn advance(
+        &mut self,
+        seconds: f32,
+        height_map: &[Vec<f32>],
+        scale: &Scale,
+        speed_factor: f32,
+    ) {
+        self.previous_distance = self.distance;
+        if self.held {
+            return;
+        }
+        if self.dwell > 0.0 {
+            self.dwell -= seconds;
+            return;
+        }
+        let length = self.length();
+        if length == 0.0 {
+            return;
+        }
+        let max_speed = self.max_speed * speed_factor;
+        let stop = self.next_stop();
+        let to_stop = (stop - self.distance).abs();
+        let remaining = self.signal_gap.map_or(to_stop, |gap| gap.min(to_stop));
+        let per_cell = scale.metres_per_cell;
+        let resistance =
+            GRAVITY * (self.grade(height_map, scale) / 100.0 + self.rolling_resistance) / per_cell;
+        let deceleration =
+            (self.braking / per_cell + resistance).max(MIN_NET_BRAKING * self.braking / per_cell);
+        let stopping_distance = self.speed * self.speed / (2.0 * deceleration);
+        let braking = remaining <= stopping_distance + self.speed * seconds;
+        let acceleration = if braking {
+            -deceleration
+        } else {
+            self.tractive_effort * (1.0 - self.speed / max_speed) / self.mass / per_cell
+                - resistance
+        };
+        let floor = if braking { 0.0 } else { CRAWL_SPEED };
+        self.speed = (self.speed + acceleration * seconds).clamp(floor, max_speed);
+        let step = (self.speed * seconds).min(remaining);
+        self.distance += if self.forward { step } else { -step };
+        if step == to_stop {
+            self.speed = 0.0;
+            self.distance = stop;
+            self.dwell = self.dwell_time;
+            let index = self.offsets.partition_point(|&offset| offset < stop);
+            self.arrived = Some(self.path[index]);
+            if stop == 0.0 || stop == length {
+                self.forward = stop == 0.0;
+                if stop == 0.0 && self.line.is_some() {
+                    self.dwell = 0.0;
+                    self.held = true;
+                }
+            }
+        } else if step == remaining {
+            self.speed = 0.0;
+        }
+        self.stopping_distance = self.speed * self.speed / (2.0 * deceleration);
+    }
+
+    // The path cells ahead of the train in the direction of travel, with how
+    // far away each one is.
+    pub fn ahead(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        let indices: Box<dyn Iterator<Item = usize>> = if self.forward {
+            let next = self
+                .offsets
+                .partition_point(|&offset| offset <= self.distance);
+            Box::new(next..self.path.len())
+        } else {
+            let next = self
+                .offsets
+                .partition_point(|&offset| offset < self.distance);
+            Box::new((0..next).rev())
+        };
+        indices.map(|index| (index, (self.offsets[index] - self.distance).abs()))
+    }
+
+    // The path cell the train has most recently passed.
+    pub fn behind(&self) -> usize {
+        if self.forward {
+            self.offsets
+                .partition_point(|&offset| offset <= self.distance)
+                .saturating_sub(1)
+        } else {
+            self.offsets
+                .partition_point(|&offset| offset < self.distance)
+                .min(self.path.len() - 1)
+        }
+    }
+
+    // Replaces the path cells from `from` to `to` with `cells`, which must
+    // start and end on the same cells, keeping the train where it is.
+    pub fn splice(&mut self, from: usize, to: usize, cells: Vec<(usize, usize)>) {
+        let old_end = self.offsets[to];
+        let end = from + cells.len() - 1;
+        self.path.splice(from..=to, cells);
+        self.offsets = offsets(&self.path);
+        if self.distance >= old_end {
+            self.distance += self.offsets[end] - old_end;
+        }
+        if self.previous_distance >= old_end {
+            self.previous_distance += self.offsets[end] - old_end;
+        }
+    }
+
+    // Grade in percent in the direction of travel, averaged around the
+    // train.
+    fn grade(&self, height_map: &[Vec<f32>], scale: &Scale) -> f32 {
+        let index = self
+            .offsets
+            .partition_point(|&offset| offset <= self.distance)
+            .saturating_sub(1);
+        let from = index.saturating_sub(GRADE_WINDOW);
+        let to = (index + GRADE_WINDOW).min(self.path.len() - 1);
+        let span = self.offsets[to] - self.offsets[from];
+        if span == 0.0 {
+            return 0.0;
         }
+        let height = |(row, col): (usize, usize)| height_map[row][col];
+        let rise = scale.grade(height(self.path[to]) - height(self.path[from]), span);
+        if self.forward { rise } else { -rise }
+    }
+
+    // Where the locomotive is drawn, a fraction of the way through the step
+    // after the last one.
+    pub fn shown_distance(&self, overstep: f32) -> f32 {
+        self.previous_distance + (self.distance - self.previous_distance) * overstep
+    }
+
+    // How far along the path a car is when the locomotive is at `head`. The
+    // wagons trail where the locomotive has been, so past an end of the path
+    // they fold back along it as if round a loop.
+    pub fn car_distance(&self, head: f32, car: usize) -> f32 {
+        let behind = car as f32 * CAR_SPACING;
+        let length = self.length();
+        let distance = if self.forward {
+            head - behind
+        } else {
+            head + behind
+        };
+        let folded = if distance < 0.0 {
+            -distance
+        } else if distance > length {
+            2.0 * length - distance
+        } else {
+            distance
+        };
+        folded.clamp(0.0, length)
+    }
+
+    // How far along the path the cars reach either side, from the
+    // locomotive to the last wagon, including any stretch they fold round.
+    pub fn extent(&self) -> (f32, f32) {
+        let reach = self.wagons as f32 * CAR_SPACING;
+        let length = self.length();
+        if self.forward {
+            let tail = self.distance - reach;
+            if tail < 0.0 {
+                (0.0, self.distance.max(-tail).min(length))
+            } else {
+                (tail, self.distance)
+            }
+        } else {
+            let tail = self.distance + reach;
+            if tail > length {
+                ((2.0 * length - tail).min(self.distance).max(0.0), length)
+            } else {
+                (self.distance, tail)
+            }
+        }
+    }
+
+    // The (row, col) position at a distance along the path, between cells if
+    // it is mid-step, and the direction of the track there in radians.
+    pub fn position_at(&self, distance: f32) -> (f32, f32, f32) {
+        if self.path.len() < 2 {
+            return (self.path[0].0 as f32, self.path[0].1 as f32, 0.0);
+        }
+        let index = self
+            .offsets
+            .partition_point(|&offset| offset <= distance)
+            .clamp(1, self.path.len() - 1);
+        let (from, to) = (self.path[index - 1], self.path[index]);
+        let span = self.offsets[index] - self.offsets[index - 1];
+        let t = ((distance - self.offsets[index - 1]) / span).clamp(0.0, 1.0);
+        let (rows, cols) = (to.0 as f32 - from.0 as f32, to.1 as f32 - from.1 as f32);
+        (
+            from.0 as f32 + rows * t,
+            from.1 as f32 + cols * t,
+            // Rows grow downwards on screen.
+            (-rows).atan2(cols),
+        )
+    }
+}
+
+fn offsets(path: &[(usize, usize)]) -> Vec<f32> {
+    let mut offsets = vec![0.0];
+    for pair in path.windows(2) {
+        let step = if pair[0].0 != pair[1].0 && pair[0].1 != pair[1].1 {
+            std::f32::consts::SQRT_2
+        } else {
+            1.0
+        };
+        offsets.push(offsets[offsets.len() - 1] + step);
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A train on a straight path of 40 cells that has just turned round at
+    // its far end.
+    fn turned() -> Train {
+        let mut train = Train::new((0..=40).map(|col| (0, col)).collect());
+        train.distance = 38.0;
+        train.forward = false;
+        train
+    }
+
+    // Seconds per fixed step.
+    const STEP: f32 = 1.0 / 64.0;
+
+    // A straight path of `cells` cells whose ground changes by `rise` per
+    // cell, a 1% grade for every 0.0001.
+    fn slope(cells: usize, rise: f32) -> (Train, Vec<Vec<f32>>) {
+        let train = Train::new((0..=cells).map(|col| (0, col)).collect());
+        let heights = vec![(0..=cells).map(|col| 1.0 + rise * col as f32).collect()];
+        (train, heights)
+    }
+
+    #[test]
+    fn heavy_trains_crawl_up_steep_grades() {
+        let (mut heavy, heights) = slope(400, 0.001);
+        heavy.mass = FREIGHT_MASS;
+        let mut light = heavy.clone();
+        light.mass = DEFAULT_MASS;
+        for _ in 0..640 {
+            heavy.advance(STEP, &heights, &Scale::default(), 1.0);
+            light.advance(STEP, &heights, &Scale::default(), 1.0);
+        }
+        assert_eq!(heavy.speed, CRAWL_SPEED);
+        assert!(light.speed > CRAWL_SPEED);
+    }
+
+    #[test]
+    fn trains_brake_to_a_stop_on_the_way_down() {
+        let (mut train, heights) = slope(200, -0.015);
+        let mut last_speed = 0.0;
+        while train.dwell == 0.0 {
+            last_speed = train.speed;
+            train.advance(STEP, &heights, &Scale::default(), 1.0);
+        }
+        assert_eq!(train.distance, train.length());
+        assert!(
+            last_speed < CRAWL_SPEED,
+            "arrived at {} cells per second",
+            last_speed
+        );
+    }
+
+    #[test]
+    fn trains_never_pass_their_stop() {
+        let (mut train, heights) = slope(200, -0.002);
+        train.stops = vec![120];
+        while train.dwell == 0.0 {
+            train.advance(STEP, &heights, &Scale::default(), 1.0);
+            assert!(train.distance <= 120.0);
+        }
+        assert_eq!(train.distance, 120.0);
+        assert_eq!(train.arrived, Some((0, 120)));
+    }
+
+    #[test]
+    fn wagons_keep_their_spacing_round_a_turn() {
+        let train = turned();
+        let cars = (0..=train.wagons)
+            .map(|car| train.car_distance(train.distance, car))
+            .collect::<Vec<_>>();
+        assert_eq!(cars, vec![38.0, 37.0, 32.0, 27.0]);
+    }
+
+    #[test]
+    fn extent_reaches_round_the_turn() {
+        assert_eq!(turned().extent(), (27.0, 40.0));
+        let mut train = turned();
+        train.distance = 20.0;
+        assert_eq!(train.extent(), (20.0, 35.0));
     }
 }
@@ -1,6 +1,23 @@
-use crate::dijkstra::{DijkstraCommand, DijkstraUpdate};
+use crate::demand::{Cargo, Demand};
+use crate::dijkstra::{
+    Alternatives, DijkstraCommand, DijkstraUpdate, JobId, JobStatus, MAX_PENDING_JOBS, Reshape,
+    RouteBreakdown, RouteJob, SearchSnapshot, Water, segment_cells,
+};
+use crate::economy::{Economy, format_money, route_price, upgrade_price, vehicle_price};
+use crate::graph::NodeKind;
+use crate::history::{History, HistoryEntry};
+use crate::line::{DEFAULT_LINE_DWELL, DEFAULT_LINE_TRAINS, Line, Lines, Schedule};
+use crate::network::{Mode, RoutePlan};
+use crate::planner::PlanJob;
+use crate::settlement::Growth;
+use crate::signals::Signals;
 use crate::state::*;
-use crate::train::Train;
+use crate::suitability::Suitability;
+use crate::terraform::{Terraform, Tool};
+use crate::traffic::{Traffic, capacity};
+use crate::train::{CAR_LENGTH, CAR_WIDTH, Car, FREIGHT_MASS, Train, TrainId};
+use crate::walkers::Walkers;
+use crate::zones::Zone;
 
 use bevy::asset::RenderAssetUsages;
 use bevy::input::common_conditions::*;
@@ -11,15 +28,30 @@ use bevy::render::render_resource::Extent3d;
 use bevy::render::render_resource::{TextureDimension, TextureFormat};
 use bevy::window::SystemCursorIcon;
 use bevy::winit::cursor::CursorIcon;
-use crossbeam_channel::{Receiver, Sender, bounded};
+use crossbeam_channel::{Receiver, Sender, TrySendError, bounded};
 
 pub fn init(width: usize, height: usize) {
     App::new()
         .add_event::<DijkstraEvent>()
+        .add_event::<CommitRoute>()
+        .add_event::<SettlementGrowth>()
         .add_plugins(DefaultPlugins)
         .insert_resource(MapState::new(width, height))
+        .init_resource::<Signals>()
+        .init_resource::<Lines>()
+        .init_resource::<Demand>()
+        .init_resource::<Economy>()
+        .init_resource::<TransportMode>()
+        .init_resource::<Traffic>()
+        .init_resource::<Settlements>()
+        .init_resource::<Walkers>()
+        .init_resource::<Terraform>()
+        .init_resource::<Reshapes>()
         .add_systems(Startup, setup)
-        .add_systems(Update, pan_camera.run_if(input_pressed(MouseButton::Left)))
+        .add_systems(
+            Update,
+            pan_camera.run_if(input_pressed(MouseButton::Left).and(not(brush_active))),
+        )
         .add_systems(Update, zoom_camera_around_cursor)
         .add_systems(
             Update,
@@ -34,98 +66,1802 @@ pub fn init(width: usize, height: usize) {
             zoom_out.run_if(input_just_pressed(KeyCode::NumpadSubtract)),
         )
         .add_systems(FixedUpdate, read_dijkstra_stream)
-        .add_systems(Update, process_dijkstra_updates)
+        .add_systems(Update, commit_routes)
+        .add_systems(Update, (request_growth, apply_growth))
+        .add_systems(
+            Update,
+            toggle_growth.run_if(input_just_pressed(KeyCode::KeyG)),
+        )
+        .add_systems(Update, refresh_stale_suitability)
+        .add_systems(
+            Update,
+            toggle_suitability_overlay.run_if(input_just_pressed(KeyCode::KeyV)),
+        )
+        .add_systems(
+            Update,
+            suggest_sites.run_if(input_just_pressed(KeyCode::KeyN)),
+        )
+        .add_systems(
+            Update,
+            query_suitability.run_if(
+                input_just_pressed(MouseButton::Left)
+                    .and(suitability_shown)
+                    .and(not(brush_active)),
+            ),
+        )
+        .add_systems(
+            Update,
+            on_mouse_left_click.run_if(
+                input_just_pressed(MouseButton::Left)
+                    .and(not(brush_active))
+                    .and(not(suitability_shown)),
+            ),
+        )
+        .add_systems(
+            Update,
+            paint_zones.run_if(input_pressed(MouseButton::Left).and(zone_brush_active)),
+        )
         .add_systems(
             Update,
-            on_mouse_left_click.run_if(input_just_pressed(MouseButton::Left)),
+            save_zones.run_if(input_just_released(MouseButton::Left)),
+        )
+        .add_systems(
+            Update,
+            cycle_zone_brush.run_if(input_just_pressed(KeyCode::KeyB)),
+        )
+        .add_systems(
+            Update,
+            (
+                cycle_terrain_tool.run_if(input_just_pressed(KeyCode::KeyR)),
+                adjust_terrain_tool.run_if(terraforming),
+                terraform.run_if(input_pressed(MouseButton::Left).and(terraforming)),
+                settle_terrain.run_if(input_just_released(MouseButton::Left).and(terraforming)),
+                send_reshape,
+                apply_water,
+            )
+                .chain(),
         )
         .add_systems(
             Update,
             on_mouse_right_click.run_if(input_just_pressed(MouseButton::Right)),
         )
-        .add_systems(Update, update_trains)
+        .add_systems(
+            FixedUpdate,
+            (
+                generate_demand,
+                run_trains,
+                dispatch_lines,
+                signal_trains,
+                update_trains,
+                load_trains,
+                measure_traffic,
+            )
+                .chain(),
+        )
+        .add_systems(FixedUpdate, walk.run_if(walkers_active))
+        .add_systems(Update, paint_trails)
+        .add_systems(
+            Update,
+            toggle_walkers.run_if(input_just_pressed(KeyCode::KeyK)),
+        )
+        .add_systems(Update, draft_line.run_if(input_just_pressed(KeyCode::KeyL)))
+        .add_systems(
+            Update,
+            tap_departure.run_if(input_just_pressed(KeyCode::KeyD)),
+        )
+        .add_systems(
+            Update,
+            create_line.run_if(input_just_pressed(KeyCode::Enter)),
+        )
+        .add_systems(Update, show_departures)
+        .add_systems(Update, place_trains)
+        .add_systems(Update, track_route_jobs)
+        .add_systems(Update, show_route_status)
+        .add_systems(
+            Update,
+            cancel_route_jobs.run_if(input_just_pressed(KeyCode::Escape)),
+        )
+        .add_systems(Update, update_search_overlay)
+        .add_systems(
+            Update,
+            toggle_search_overlay.run_if(input_just_pressed(KeyCode::KeyF)),
+        )
+        .add_systems(Update, show_alternatives)
+        .add_systems(Update, pick_alternative)
+        .add_systems(
+            Update,
+            discard_alternatives.run_if(input_just_pressed(KeyCode::Escape)),
+        )
+        .add_systems(
+            Update,
+            place_station.run_if(input_just_pressed(KeyCode::KeyS)),
+        )
+        .add_systems(Update, demolish.run_if(input_just_pressed(KeyCode::KeyX)))
+        .add_systems(
+            Update,
+            dispatch_train.run_if(input_just_pressed(KeyCode::KeyT)),
+        )
+        .add_systems(Update, autoplan.run_if(input_just_pressed(KeyCode::KeyP)))
+        .add_systems(
+            Update,
+            upgrade_infrastructure.run_if(input_just_pressed(KeyCode::KeyU)),
+        )
+        .add_systems(
+            Update,
+            toggle_transport_mode.run_if(input_just_pressed(KeyCode::KeyM)),
+        )
+        .add_systems(Update, undo_edit.run_if(input_just_pressed(KeyCode::KeyZ)))
+        .add_systems(Update, redo_edit.run_if(input_just_pressed(KeyCode::KeyY)))
         .run();
 }
 
-#[derive(Component)]
-struct MainCamera;
+#[derive(Component)]
+struct MainCamera;
+
+#[derive(Resource, Deref)]
+struct DijkstraReceiver(Receiver<DijkstraUpdate>);
+
+#[derive(Event)]
+struct DijkstraEvent(DijkstraUpdate);
+
+#[derive(Event)]
+struct CommitRoute(RoutePlan);
+
+#[derive(Event)]
+struct SettlementGrowth(Vec<Growth>);
+
+// Seconds between two steps of settlement growth.
+const GROWTH_INTERVAL: f32 = 0.25;
+
+// Whether towns are growing, and whether the worker is busy with a step.
+#[derive(Resource, Default)]
+struct Settlements {
+    growing: bool,
+    in_flight: bool,
+    since_step: f32,
+}
+
+// Reshaped ground waiting for the worker to take it, the water it found
+// for the latest reshape, and how many reshapes there were, so water found
+// for an earlier one is ignored.
+#[derive(Resource, Default)]
+struct Reshapes {
+    pending: Option<Reshape>,
+    water: Option<Water>,
+    latest: u64,
+}
+
+#[derive(Resource)]
+struct TrainSprite(Handle<Image>);
+
+// Whether new routes and vehicles are rail or road.
+#[derive(Resource, Default)]
+struct TransportMode(Mode);
+
+#[derive(Resource)]
+struct DijkstraCommandHolder {
+    a: (usize, usize),
+    b: (usize, usize),
+}
+
+#[derive(Resource)]
+struct DijkstraCommandSender(Sender<DijkstraCommand>);
+
+#[derive(Resource, Default)]
+struct RouteJobs {
+    next_id: JobId,
+    queued: Vec<JobId>,
+    running: Option<JobId>,
+    message: String,
+}
+
+impl RouteJobs {
+    fn pending(&self) -> usize {
+        self.queued.len() + self.running.iter().count()
+    }
+}
+
+#[derive(Component)]
+struct RouteStatusText;
+
+#[derive(Resource)]
+struct SearchOverlay {
+    image: Handle<Image>,
+    frontier: Vec<(usize, usize)>,
+}
+
+#[derive(Component)]
+struct SearchOverlaySprite;
+
+// The suitability map, computed when it is first needed after the network
+// changed, and the overlay it is drawn on.
+#[derive(Resource)]
+struct SuitabilityLayer {
+    map: Option<Suitability>,
+    image: Handle<Image>,
+    // The map changed since the suitability map was computed, and seconds
+    // since it was.
+    stale: bool,
+    since_refresh: f32,
+}
+
+#[derive(Component)]
+struct SuitabilityOverlaySprite;
+
+// Seconds between computing a shown suitability map again while the map
+// keeps changing.
+const SUITABILITY_REFRESH_INTERVAL: f32 = 2.0;
+
+// Share of the treasury the autoplanner may spend.
+const AUTOPLAN_BUDGET_SHARE: f32 = 0.5;
+
+// The heat map of trail wear, the cells painted on it last time, and when
+// it was last repainted and reported.
+#[derive(Resource)]
+struct TrailOverlay {
+    image: Handle<Image>,
+    painted: Vec<(usize, usize)>,
+    since_paint: f32,
+    since_report: f32,
+}
+
+#[derive(Component)]
+struct TrailOverlaySprite;
+
+// Seconds between repaints of the trail heat map and between reports of how
+// trails compare with the built network.
+const TRAIL_PAINT_INTERVAL: f32 = 0.5;
+const TRAIL_REPORT_INTERVAL: f32 = 10.0;
+
+// How many sites the suggest command offers, and how far apart they are.
+const SUGGESTED_SITES: usize = 5;
+const SITE_SPACING: usize = 60;
+const SITE_COLOR: [u8; 4] = [255, 255, 255, 255];
+
+// The zone the brush paints, if it is on, whether painted zones are still
+// to be saved, and the overlay showing them.
+#[derive(Resource)]
+struct ZoneLayer {
+    brush: Option<Zone>,
+    unsaved: bool,
+    image: Handle<Image>,
+}
+
+// Cells from the cursor the zone brush paints.
+const BRUSH_RADIUS: usize = 6;
+
+#[derive(Resource)]
+struct RouteProposal {
+    alternatives: Option<Alternatives>,
+    preview: Handle<Image>,
+}
+
+#[derive(Component)]
+struct AlternativesPanel;
+
+#[derive(Component)]
+struct RouteReportText;
+
+#[derive(Component)]
+struct DeparturesBoardText;
+
+const ALTERNATIVE_COLORS: [[u8; 4]; 3] =
+    [[255, 140, 0, 255], [255, 0, 255, 255], [0, 200, 255, 255]];
+const ALTERNATIVE_KEYS: [KeyCode; 3] = [KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3];
+
+const VISITED_COLOR: [u8; 4] = [255, 220, 0, 90];
+const FRONTIER_COLOR: [u8; 4] = [0, 255, 255, 200];
+
+fn read_dijkstra_stream(
+    dijkstra_receiver: Res<DijkstraReceiver>,
+    mut event_writer: EventWriter<DijkstraEvent>,
+    mut commit_writer: EventWriter<CommitRoute>,
+    mut growth_writer: EventWriter<SettlementGrowth>,
+    mut route_jobs: ResMut<RouteJobs>,
+    mut reshapes: ResMut<Reshapes>,
+) {
+    for update in dijkstra_receiver.try_iter() {
+        match update {
+            DijkstraUpdate::Planned(link) => {
+                route_jobs.message = format!("Autoplan: {}", link.reason);
+                commit_writer.send(CommitRoute(link.plan));
+            }
+            DijkstraUpdate::Growth(growth) => {
+                growth_writer.send(SettlementGrowth(growth));
+            }
+            DijkstraUpdate::Water(water) if water.id == reshapes.latest => {
+                reshapes.water = Some(water);
+            }
+            DijkstraUpdate::Water(_) => {}
+            update => {
+                event_writer.send(DijkstraEvent(update));
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn commit_routes(
+    mut commands: Commands,
+    mut event_reader: EventReader<CommitRoute>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    mut map_state: ResMut<MapState>,
+    train_sprite: Res<TrainSprite>,
+    mut history: ResMut<History>,
+    mut economy: ResMut<Economy>,
+    mut route_jobs: ResMut<RouteJobs>,
+    mut report_text: Single<&mut Text, With<RouteReportText>>,
+) {
+    for CommitRoute(plan) in event_reader.read() {
+        // The plan was found on a snapshot the network may have moved on from.
+        if !map_state.network.admits(plan) {
+            route_jobs.message = "Route refused: something was built in its way".to_string();
+            continue;
+        }
+        let construction = route_price(&plan.breakdown);
+        let purchases = vehicle_price(plan.mode);
+        if let Err(reason) = economy.buy(construction, purchases) {
+            route_jobs.message = format!("Route refused: {}", reason);
+            continue;
+        }
+        let image = images.get_mut(&image_handle.0).unwrap();
+        let edit = map_state.commit(plan, image);
+        println!(
+            "{:?} network graph: {}",
+            plan.mode,
+            map_state
+                .graph(plan.mode)
+                .summary(&map_state.dijkstra.scale)
+        );
+        let report = format_breakdown(&plan.breakdown);
+        println!("Route built: {}", report.replace('\n', "; "));
+        report_text.0 = format!("Last route\n{}", report);
+
+        // create a train or a bus that moves along the path
+        let train = match plan.mode {
+            Mode::Rail => Train::new(plan.cells()),
+            Mode::Road => Train::road(plan.cells(), Cargo::Passengers),
+        };
+        let id = history.next_train();
+        spawn_train(&mut commands, &train_sprite, id, train.clone());
+        history.record(HistoryEntry {
+            edit,
+            spawned: vec![(id, train)],
+            despawned: Vec::new(),
+            lines: Vec::new(),
+            construction,
+            purchases,
+        });
+    }
+}
+
+const LOCOMOTIVE_COLOR: Color = Color::srgb(0.8, 0.1, 0.1);
+const PASSENGER_COLOR: Color = Color::srgb(0.95, 0.85, 0.2);
+const FREIGHT_COLOR: Color = Color::srgb(0.45, 0.3, 0.15);
+const BUS_COLOR: Color = Color::srgb(0.1, 0.4, 0.9);
+const TRUCK_COLOR: Color = Color::srgb(0.2, 0.6, 0.2);
+
+// Spawns a train as a locomotive and its wagons, each a child entity. A
+// road vehicle is a single car.
+fn spawn_train(commands: &mut Commands, train_sprite: &TrainSprite, id: TrainId, train: Train) {
+    let wagon_color = match train.cargo {
+        Cargo::Passengers => PASSENGER_COLOR,
+        Cargo::Freight => FREIGHT_COLOR,
+    };
+    let locomotive_color = match (train.mode, train.cargo) {
+        (Mode::Rail, _) => LOCOMOTIVE_COLOR,
+        (Mode::Road, Cargo::Passengers) => BUS_COLOR,
+        (Mode::Road, Cargo::Freight) => TRUCK_COLOR,
+    };
+    let cars = train.wagons + 1;
+    commands
+        .spawn((
+            train,
+            id,
+            Transform::from_xyz(0.0, 0.0, 10.0),
+            Visibility::default(),
+        ))
+        .with_children(|parent| {
+            for car in 0..cars {
+                parent.spawn((
+                    Sprite {
+                        image: train_sprite.0.clone(),
+                        color: if car == 0 {
+                            locomotive_color
+                        } else {
+                            wagon_color
+                        },
+                        custom_size: Some(Vec2::new(CAR_LENGTH, CAR_WIDTH)),
+                        ..default()
+                    },
+                    Car(car),
+                ));
+            }
+        });
+}
+
+fn place_station(
+    query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: Query<&Window>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    mut map_state: ResMut<MapState>,
+    mut history: ResMut<History>,
+) {
+    let Some(cell) = cursor_cell(&query, &windows, &map_state) else {
+        return;
+    };
+    if map_state.dijkstra.is_water[cell.0][cell.1]
+        || map_state.near_station(cell.0, cell.1).is_some()
+    {
+        return;
+    }
+    let image = images.get_mut(&image_handle.0).unwrap();
+    let edit = map_state.place_station(cell, image);
+    history.record(HistoryEntry {
+        edit,
+        spawned: Vec::new(),
+        despawned: Vec::new(),
+        lines: Vec::new(),
+        construction: 0.0,
+        purchases: 0.0,
+    });
+}
+
+// Demolishes the station under the cursor, or else the most recently built
+// route there. Trains that can no longer run are removed.
+#[allow(clippy::too_many_arguments)]
+fn demolish(
+    mut commands: Commands,
+    query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: Query<&Window>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    mut map_state: ResMut<MapState>,
+    trains: Query<(Entity, &TrainId, &Train)>,
+    mut history: ResMut<History>,
+) {
+    let Some(cell) = cursor_cell(&query, &windows, &map_state) else {
+        return;
+    };
+    let image = images.get_mut(&image_handle.0).unwrap();
+    let edit = if let Some(station) = map_state.near_station(cell.0, cell.1) {
+        map_state.demolish_station(station, image)
+    } else if let Some(id) = map_state.network.route_near(cell, 2) {
+        map_state.demolish_route(id, image)
+    } else {
+        return;
+    };
+    let network = &map_state.network;
+    let mut despawned = Vec::new();
+    for (entity, &id, train) in trains.iter() {
+        let (first, last) = (train.path[0], train.path[train.path.len() - 1]);
+        let broken = !network.stations.contains(&first)
+            || !network.stations.contains(&last)
+            || train
+                .path
+                .iter()
+                .any(|&(row, col)| network.level(train.mode)[row][col] == 0);
+        if broken {
+            commands.entity(entity).despawn_recursive();
+            despawned.push((id, train.clone()));
+        }
+    }
+    history.record(HistoryEntry {
+        edit,
+        spawned: Vec::new(),
+        despawned,
+        lines: Vec::new(),
+        construction: 0.0,
+        purchases: 0.0,
+    });
+}
+
+// Sends a train, or a bus in road mode, from the station picked with the
+// left button to the station under the cursor, over what is already built.
+#[allow(clippy::too_many_arguments)]
+fn dispatch_train(
+    mut commands: Commands,
+    query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: Query<&Window>,
+    keys: Res<ButtonInput<KeyCode>>,
+    map_state: Res<MapState>,
+    dijkstra_command_holder: Res<DijkstraCommandHolder>,
+    train_sprite: Res<TrainSprite>,
+    transport_mode: Res<TransportMode>,
+    mut route_jobs: ResMut<RouteJobs>,
+    mut history: ResMut<History>,
+    mut economy: ResMut<Economy>,
+) {
+    let Some((row, col)) = cursor_cell(&query, &windows, &map_state) else {
+        return;
+    };
+    let mode = transport_mode.0;
+    let to = map_state.near_station(row, col).unwrap_or((row, col));
+    match map_state.graph(mode).route(dijkstra_command_holder.a, to) {
+        Ok(path) => {
+            let purchases = vehicle_price(mode);
+            if let Err(reason) = economy.buy(0.0, purchases) {
+                route_jobs.message = format!("Cannot buy vehicle: {}", reason);
+                return;
+            }
+            route_jobs.message = format!(
+                "{:?} vehicle dispatched from {:?} to {:?}",
+                mode, dijkstra_command_holder.a, to
+            );
+            // Shift sends a heavy freight train that struggles on steep track,
+            // or a truck.
+            let freight = keys.pressed(KeyCode::ShiftLeft) || keys.pressed(KeyCode::ShiftRight);
+            let train = match (mode, freight) {
+                (Mode::Rail, false) => Train::new(path),
+                (Mode::Rail, true) => {
+                    let mut train = Train::new(path);
+                    train.mass = FREIGHT_MASS;
+                    train.cargo = Cargo::Freight;
+                    train
+                }
+                (Mode::Road, false) => Train::road(path, Cargo::Passengers),
+                (Mode::Road, true) => Train::road(path, Cargo::Freight),
+            };
+            let id = history.next_train();
+            spawn_train(&mut commands, &train_sprite, id, train.clone());
+            history.record(HistoryEntry {
+                edit: Edit::default(),
+                spawned: vec![(id, train)],
+                despawned: Vec::new(),
+                lines: Vec::new(),
+                construction: 0.0,
+                purchases,
+            });
+        }
+        Err(error) => {
+            route_jobs.message = format!("Cannot dispatch train: {}", error);
+        }
+    }
+}
+
+fn toggle_transport_mode(
+    mut transport_mode: ResMut<TransportMode>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    transport_mode.0 = match transport_mode.0 {
+        Mode::Rail => Mode::Road,
+        Mode::Road => Mode::Rail,
+    };
+    route_jobs.message = format!("Building {:?}", transport_mode.0);
+}
+
+// Hands the worker the stations and their demand to connect automatically,
+// within a share of the treasury.
+fn autoplan(
+    map_state: Res<MapState>,
+    mut demand: ResMut<Demand>,
+    economy: Res<Economy>,
+    transport_mode: Res<TransportMode>,
+    dijkstra_command_sender: Res<DijkstraCommandSender>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    if route_jobs.pending() >= MAX_PENDING_JOBS {
+        route_jobs.message = format!("Route queue full ({} jobs)", MAX_PENDING_JOBS);
+        return;
+    }
+    let job = PlanJob {
+        id: route_jobs.next_id,
+        mode: transport_mode.0,
+        network: map_state.network.clone(),
+        graph: map_state.graph(transport_mode.0).clone(),
+        demand: demand.matrix(&map_state),
+        budget: AUTOPLAN_BUDGET_SHARE * economy.treasury.max(0.0),
+    };
+    let id = job.id;
+    let budget = job.budget;
+    match dijkstra_command_sender
+        .0
+        .try_send(DijkstraCommand::AutoPlan(job))
+    {
+        Ok(()) => {
+            route_jobs.next_id += 1;
+            route_jobs.queued.push(id);
+            route_jobs.message = format!("Autoplan #{} queued with {}", id, format_money(budget));
+        }
+        Err(TrySendError::Full(_)) => {
+            route_jobs.message = "Route worker busy, try again".to_string();
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            route_jobs.message = "Route worker stopped".to_string();
+        }
+    }
+}
+
+fn toggle_walkers(
+    mut walkers: ResMut<Walkers>,
+    mut visibility: Single<&mut Visibility, With<TrailOverlaySprite>>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    walkers.active = !walkers.active;
+    if walkers.active {
+        **visibility = Visibility::Visible;
+        route_jobs.message = "Walkers out, trails shown".to_string();
+    } else {
+        // The trails keep their wear, and carry on from it when walkers are
+        // sent out again.
+        walkers.walkers.clear();
+        **visibility = Visibility::Hidden;
+        route_jobs.message = "Walkers stopped, trails hidden".to_string();
+    }
+}
+
+fn walkers_active(walkers: Res<Walkers>) -> bool {
+    walkers.active
+}
+
+fn walk(map_state: Res<MapState>, mut walkers: ResMut<Walkers>, time: Res<Time>) {
+    walkers.step(&map_state, &mut rand::rng());
+    walkers.decay(time.delta_secs());
+}
+
+// Repaints the heat map of wear, hottest trails brightest, and now and then
+// logs how closely the trails follow the built network.
+fn paint_trails(
+    map_state: Res<MapState>,
+    walkers: Res<Walkers>,
+    mut overlay: ResMut<TrailOverlay>,
+    mut images: ResMut<Assets<Image>>,
+    time: Res<Time>,
+) {
+    if !walkers.active {
+        return;
+    }
+    overlay.since_paint += time.delta_secs();
+    overlay.since_report += time.delta_secs();
+    if overlay.since_report >= TRAIL_REPORT_INTERVAL {
+        overlay.since_report = 0.0;
+        let (trails, share) = walkers.overlap(&map_state);
+        println!(
+            "Trails: {} well trodden cells, {:.0}% on built routes",
+            trails,
+            100.0 * share
+        );
+    }
+    if overlay.since_paint < TRAIL_PAINT_INTERVAL {
+        return;
+    }
+    overlay.since_paint = 0.0;
+    let image = images.get_mut(&overlay.image).unwrap();
+    let mut paint = |(row, col): (usize, usize), color: [u8; 4]| {
+        image
+            .pixel_bytes_mut(UVec3::new(col as u32, row as u32, 0))
+            .unwrap()
+            .copy_from_slice(&color);
+    };
+    for &cell in overlay.painted.iter() {
+        paint(cell, [0; 4]);
+    }
+    for &cell in walkers.wear.keys() {
+        let heat = walkers.trodden(cell);
+        paint(
+            cell,
+            [
+                255,
+                (255.0 * heat) as u8,
+                (120.0 * heat) as u8,
+                (60.0 + 195.0 * heat) as u8,
+            ],
+        );
+    }
+    overlay.painted = walkers.wear.keys().cloned().collect();
+}
+
+// Switches the brush from off to forbidden, penalty, erase and back off.
+fn cycle_zone_brush(mut layer: ResMut<ZoneLayer>, mut route_jobs: ResMut<RouteJobs>) {
+    layer.brush = match layer.brush {
+        None => Some(Zone::Forbidden),
+        Some(Zone::Forbidden) => Some(Zone::Penalty),
+        Some(Zone::Penalty) => Some(Zone::Free),
+        Some(Zone::Free) => None,
+    };
+    route_jobs.message = match layer.brush {
+        Some(Zone::Forbidden) => "Brush: no-build zone, drag to paint".to_string(),
+        Some(Zone::Penalty) => "Brush: penalty zone, drag to paint".to_string(),
+        Some(Zone::Free) => "Brush: erase zones, drag to paint".to_string(),
+        None => "Brush off".to_string(),
+    };
+}
+
+// Saves the zones at the end of every stroke that changed them.
+fn save_zones(map_state: Res<MapState>, mut layer: ResMut<ZoneLayer>) {
+    if !layer.unsaved {
+        return;
+    }
+    layer.unsaved = false;
+    match map_state.save_zones() {
+        Ok(path) => println!("Zones saved to {}", path),
+        Err(error) => println!("Could not save zones: {}", error),
+    }
+}
+
+fn zone_brush_active(layer: Res<ZoneLayer>) -> bool {
+    layer.brush.is_some()
+}
+
+// Whether dragging paints rather than pans the map.
+fn brush_active(layer: Res<ZoneLayer>, terraform: Res<Terraform>) -> bool {
+    layer.brush.is_some() || terraform.tool.is_some()
+}
+
+fn paint_zones(
+    query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: Query<&Window>,
+    mut map_state: ResMut<MapState>,
+    mut layer: ResMut<ZoneLayer>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let (Some(zone), Some(cell)) = (layer.brush, cursor_cell(&query, &windows, &map_state)) else {
+        return;
+    };
+    let changed = map_state.paint_zone(cell, BRUSH_RADIUS, zone);
+    if changed.is_empty() {
+        return;
+    }
+    layer.unsaved = true;
+    let image = images.get_mut(&layer.image).unwrap();
+    for cell in changed {
+        paint_zone(image, cell, zone);
+    }
+}
+
+fn paint_zone(image: &mut Image, (row, col): (usize, usize), zone: Zone) {
+    let color = match zone {
+        Zone::Free => [0, 0, 0, 0],
+        Zone::Penalty => [255, 140, 0, 90],
+        Zone::Forbidden => [255, 0, 0, 110],
+    };
+    image
+        .pixel_bytes_mut(UVec3::new(col as u32, row as u32, 0))
+        .unwrap()
+        .copy_from_slice(&color);
+}
+
+fn cycle_terrain_tool(mut terraform: ResMut<Terraform>, mut route_jobs: ResMut<RouteJobs>) {
+    terraform.tool = Tool::next(terraform.tool);
+    route_jobs.message = match terraform.tool {
+        Some(tool) => format!(
+            "Terrain: {}, radius {}, strength {}, [ ] to resize, , . to weaken or strengthen",
+            tool.name(),
+            terraform.radius,
+            terraform.strength
+        ),
+        None => "Terrain tools off".to_string(),
+    };
+}
+
+// The zone brush takes precedence while both are on.
+fn terraforming(layer: Res<ZoneLayer>, terraform: Res<Terraform>) -> bool {
+    layer.brush.is_none() && terraform.tool.is_some()
+}
+
+fn adjust_terrain_tool(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut terraform: ResMut<Terraform>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    if keys.just_pressed(KeyCode::BracketLeft) || keys.just_pressed(KeyCode::BracketRight) {
+        terraform.resize(keys.just_pressed(KeyCode::BracketRight));
+    } else if keys.just_pressed(KeyCode::Comma) || keys.just_pressed(KeyCode::Period) {
+        terraform.strengthen(keys.just_pressed(KeyCode::Period));
+    } else {
+        return;
+    }
+    route_jobs.message = format!(
+        "Terrain brush: radius {}, strength {}",
+        terraform.radius, terraform.strength
+    );
+}
+
+fn terraform(
+    query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: Query<&Window>,
+    mut map_state: ResMut<MapState>,
+    mut terraform: ResMut<Terraform>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    time: Res<Time>,
+) {
+    let Some(cell) = cursor_cell(&query, &windows, &map_state) else {
+        return;
+    };
+    let changed = terraform.brush(&mut map_state.ground, cell, time.delta_secs());
+    if changed.is_empty() {
+        return;
+    }
+    let image = images.get_mut(&image_handle.0).unwrap();
+    map_state.reshape(&changed, image);
+}
+
+// Once a stroke ends, queues the reshaped ground for the worker, which
+// fills it with water and plans routes on it from then on.
+fn settle_terrain(
+    map_state: Res<MapState>,
+    mut terraform: ResMut<Terraform>,
+    mut reshapes: ResMut<Reshapes>,
+) {
+    if !terraform.finish() {
+        return;
+    }
+    reshapes.latest += 1;
+    reshapes.pending = Some(Reshape {
+        id: reshapes.latest,
+        ground: map_state.ground.clone(),
+    });
+}
+
+// Hands the worker the latest reshaped ground, trying again next frame
+// while its queue is full.
+fn send_reshape(
+    mut reshapes: ResMut<Reshapes>,
+    dijkstra_command_sender: Res<DijkstraCommandSender>,
+) {
+    let Some(reshape) = reshapes.pending.take() else {
+        return;
+    };
+    match dijkstra_command_sender
+        .0
+        .try_send(DijkstraCommand::Terrain(reshape))
+    {
+        Ok(()) => {}
+        Err(TrySendError::Full(DijkstraCommand::Terrain(reshape))) => {
+            reshapes.pending = Some(reshape);
+        }
+        Err(_) => println!("Could not hand the reshaped terrain to the router"),
+    }
+}
+
+// Shows the water the worker found on the reshaped ground. Catchments
+// leave out water, so they are worked out again.
+fn apply_water(
+    mut reshapes: ResMut<Reshapes>,
+    mut map_state: ResMut<MapState>,
+    mut demand: ResMut<Demand>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let Some(water) = reshapes.water.take() else {
+        return;
+    };
+    let image = images.get_mut(&image_handle.0).unwrap();
+    map_state.set_water(water, image);
+    demand.forget_catchments();
+}
+
+fn toggle_growth(mut settlements: ResMut<Settlements>, mut route_jobs: ResMut<RouteJobs>) {
+    settlements.growing = !settlements.growing;
+    route_jobs.message = if settlements.growing {
+        "Settlement growth on".to_string()
+    } else {
+        "Settlement growth paused".to_string()
+    };
+}
+
+// Hands the worker a snapshot of the network for the next growth step once
+// it has finished the last one.
+fn request_growth(
+    map_state: Res<MapState>,
+    mut settlements: ResMut<Settlements>,
+    dijkstra_command_sender: Res<DijkstraCommandSender>,
+    time: Res<Time>,
+) {
+    settlements.since_step += time.delta_secs();
+    if !settlements.growing || settlements.in_flight || settlements.since_step < GROWTH_INTERVAL {
+        return;
+    }
+    let command = DijkstraCommand::Grow(map_state.network.clone());
+    if dijkstra_command_sender.0.try_send(command).is_ok() {
+        settlements.in_flight = true;
+        settlements.since_step = 0.0;
+    }
+}
+
+// Builds what the worker grew. Growth is free and not part of the undo
+// history. Houses on cells built on since the snapshot are dropped, and so
+// are roads and streets that would now run through something.
+fn apply_growth(
+    mut event_reader: EventReader<SettlementGrowth>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    mut map_state: ResMut<MapState>,
+    mut settlements: ResMut<Settlements>,
+) {
+    for SettlementGrowth(growth) in event_reader.read() {
+        settlements.in_flight = false;
+        let image = images.get_mut(&image_handle.0).unwrap();
+        for growth in growth.iter() {
+            match growth {
+                Growth::Town(centre) => {
+                    map_state.found_town(*centre, image);
+                }
+                Growth::House((row, col)) => {
+                    let network = &map_state.network;
+                    if network.house_level[*row][*col] == 0
+                        && network.rail_level[*row][*col] == 0
+                        && network.road_level[*row][*col] == 0
+                    {
+                        map_state.build_house((*row, *col), image);
+                    }
+                }
+                Growth::Road(plan) if map_state.network.admits(plan) => {
+                    map_state.commit(plan, image);
+                }
+                Growth::Street(plan) if map_state.network.admits(plan) => {
+                    map_state.lay(plan, image);
+                }
+                Growth::Road(_) | Growth::Street(_) => {}
+            }
+        }
+    }
+}
+
+// Starts picking stations for a new line, or drops the one being picked.
+fn draft_line(mut lines: ResMut<Lines>, mut route_jobs: ResMut<RouteJobs>) {
+    lines.tapped.clear();
+    if lines.draft.take().is_some() {
+        route_jobs.message = "Line discarded".to_string();
+    } else {
+        lines.draft = Some(Vec::new());
+        route_jobs.message =
+            "New line: click stations in order, D to tap out departures, Enter to create"
+                .to_string();
+    }
+}
+
+// While a line is being picked, records a departure for its timetable. The
+// timetable repeats from the first tap once the line is created.
+fn tap_departure(mut lines: ResMut<Lines>, mut route_jobs: ResMut<RouteJobs>, time: Res<Time>) {
+    if lines.draft.is_none() {
+        return;
+    }
+    let now = time.elapsed_secs();
+    let first = *lines.tapped.first().unwrap_or(&now);
+    lines.tapped.push(now);
+    route_jobs.message = format!(
+        "New line: departure {} at +{:.0} s, Enter to create",
+        lines.tapped.len(),
+        now - first
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_line(
+    mut commands: Commands,
+    map_state: Res<MapState>,
+    transport_mode: Res<TransportMode>,
+    train_sprite: Res<TrainSprite>,
+    mut lines: ResMut<Lines>,
+    mut route_jobs: ResMut<RouteJobs>,
+    mut history: ResMut<History>,
+    mut economy: ResMut<Economy>,
+    time: Res<Time>,
+) {
+    let Some(stations) = lines.draft.clone() else {
+        return;
+    };
+    if stations.len() < 2 {
+        route_jobs.message = "A line needs at least two stations".to_string();
+        return;
+    }
+    // The line runs on the network that has all its stations, the one being
+    // built if both do.
+    let mode = [transport_mode.0, Mode::Rail, Mode::Road]
+        .into_iter()
+        .find(|&mode| {
+            let nodes = &map_state.graph(mode).nodes;
+            stations
+                .iter()
+                .all(|station| nodes.get(station) == Some(&NodeKind::Station))
+        });
+    let Some(mode) = mode else {
+        route_jobs.message =
+            "Cannot create line: its stations are not all on the railway or all on the roads"
+                .to_string();
+        return;
+    };
+    let line = Line {
+        mode,
+        stations,
+        trains: DEFAULT_LINE_TRAINS,
+        dwell: DEFAULT_LINE_DWELL,
+        // The first train leaves straight away.
+        next_departure: time.elapsed_secs(),
+        schedule: Schedule::tapped(&lines.tapped, time.elapsed_secs()),
+    };
+    let id = lines.next_id;
+    let trains = match (0..line.trains)
+        .map(|_| line.train(id, map_state.graph(mode)))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(trains) => trains,
+        Err(error) => {
+            route_jobs.message = format!("Cannot create line: {}", error);
+            return;
+        }
+    };
+    let purchases = vehicle_price(mode) * trains.len() as f32;
+    if let Err(reason) = economy.buy(0.0, purchases) {
+        route_jobs.message = format!("Cannot buy trains for the line: {}", reason);
+        return;
+    }
+    let spawned = trains
+        .into_iter()
+        .map(|train| {
+            let train_id = history.next_train();
+            spawn_train(&mut commands, &train_sprite, train_id, train.clone());
+            (train_id, train)
+        })
+        .collect();
+    route_jobs.message = format!(
+        "Line {} created with {} stations and {} trains",
+        id,
+        line.stations.len(),
+        line.trains
+    );
+    lines.lines.insert(id, line.clone());
+    lines.next_id += 1;
+    lines.draft = None;
+    lines.tapped.clear();
+    history.record(HistoryEntry {
+        edit: Edit::default(),
+        spawned,
+        despawned: Vec::new(),
+        lines: vec![(id, line)],
+        construction: 0.0,
+        purchases,
+    });
+}
+
+// Shows what is waiting at the station picked with the left button, what
+// has been delivered there, and when trains next leave it.
+fn show_departures(
+    map_state: Res<MapState>,
+    lines: Res<Lines>,
+    demand: Res<Demand>,
+    dijkstra_command_holder: Res<DijkstraCommandHolder>,
+    trains: Query<&Train>,
+    mut board_text: Single<&mut Text, With<DeparturesBoardText>>,
+    time: Res<Time>,
+) {
+    let station = dijkstra_command_holder.a;
+    if !map_state.network.stations.contains(&station) {
+        board_text.0.clear();
+        return;
+    }
+    let delivered = |cargo| {
+        demand
+            .delivered_to
+            .get(&station)
+            .and_then(|delivered| delivered.get(&cargo))
+            .cloned()
+            .unwrap_or(0.0)
+    };
+    let total = |cargo| demand.delivered.get(&cargo).cloned().unwrap_or(0.0);
+    let mut board = format!(
+        "Station {:?}\nWaiting: {:.0} passengers, {:.0} t freight\nDelivered here: {:.0} passengers, {:.0} t freight\nDelivered in total: {:.0} passengers, {:.0} t freight",
+        station,
+        demand.waiting_at(station, Cargo::Passengers),
+        demand.waiting_at(station, Cargo::Freight),
+        delivered(Cargo::Passengers),
+        delivered(Cargo::Freight),
+        total(Cargo::Passengers),
+        total(Cargo::Freight),
+    );
+    let departures = lines.departures(station, trains.iter(), time.elapsed_secs());
+    if !departures.is_empty() {
+        board.push_str("\nDepartures");
+    }
+    for departure in departures {
+        board.push_str(&format!(
+            "\nLine {} to {:?} in {:.0} s",
+            departure.line, departure.destination, departure.seconds
+        ));
+    }
+    board_text.0 = board;
+}
+
+#[allow(clippy::too_many_arguments)]
+fn undo_edit(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    mut map_state: ResMut<MapState>,
+    train_sprite: Res<TrainSprite>,
+    trains: Query<(Entity, &TrainId, &Train)>,
+    mut lines: ResMut<Lines>,
+    mut history: ResMut<History>,
+    mut economy: ResMut<Economy>,
+) {
+    if !(keys.pressed(KeyCode::ControlLeft) || keys.pressed(KeyCode::ControlRight)) {
+        return;
+    }
+    let Some(mut entry) = history.undo.pop() else {
+        return;
+    };
+    economy.refund(entry.construction, entry.purchases);
+    let image = images.get_mut(&image_handle.0).unwrap();
+    map_state.undo(&entry.edit, image);
+    for (id, line) in entry.lines.iter_mut() {
+        if let Some(current) = lines.lines.remove(id) {
+            *line = current;
+        }
+    }
+    swap_trains(
+        &mut commands,
+        &train_sprite,
+        &trains,
+        &mut entry.spawned,
+        &entry.despawned,
+    );
+    history.redo.push(entry);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn redo_edit(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    mut map_state: ResMut<MapState>,
+    train_sprite: Res<TrainSprite>,
+    trains: Query<(Entity, &TrainId, &Train)>,
+    mut lines: ResMut<Lines>,
+    mut history: ResMut<History>,
+    mut economy: ResMut<Economy>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    if !(keys.pressed(KeyCode::ControlLeft) || keys.pressed(KeyCode::ControlRight)) {
+        return;
+    }
+    let Some(mut entry) = history.redo.pop() else {
+        return;
+    };
+    if let Err(reason) = economy.buy(entry.construction, entry.purchases) {
+        route_jobs.message = format!("Cannot redo: {}", reason);
+        history.redo.push(entry);
+        return;
+    }
+    let image = images.get_mut(&image_handle.0).unwrap();
+    map_state.redo(&entry.edit, image);
+    lines.lines.extend(entry.lines.iter().cloned());
+    swap_trains(
+        &mut commands,
+        &train_sprite,
+        &trains,
+        &mut entry.despawned,
+        &entry.spawned,
+    );
+    history.undo.push(entry);
+}
+
+// Despawns the trains in `remove`, remembering where they were, and spawns
+// the trains in `restore` again. Trains are found by id, as undo and redo
+// give them new entities.
+fn swap_trains(
+    commands: &mut Commands,
+    train_sprite: &TrainSprite,
+    trains: &Query<(Entity, &TrainId, &Train)>,
+    remove: &mut [(TrainId, Train)],
+    restore: &[(TrainId, Train)],
+) {
+    for (id, train) in remove.iter_mut() {
+        let Some((entity, _, current)) = trains.iter().find(|(_, other, _)| *other == id) else {
+            continue;
+        };
+        *train = current.clone();
+        commands.entity(entity).despawn_recursive();
+    }
+    for (id, train) in restore.iter() {
+        spawn_train(commands, train_sprite, *id, train.clone());
+    }
+}
 
-#[derive(Resource, Deref)]
-struct DijkstraReceiver(Receiver<DijkstraUpdate>);
+fn track_route_jobs(
+    mut event_reader: EventReader<DijkstraEvent>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    for event in event_reader.read() {
+        let DijkstraEvent(DijkstraUpdate::Job(id, status)) = event else {
+            continue;
+        };
+        route_jobs.queued.retain(|queued| queued != id);
+        if *status == JobStatus::Running {
+            route_jobs.running = Some(*id);
+            continue;
+        }
+        if route_jobs.running == Some(*id) {
+            route_jobs.running = None;
+        }
+        route_jobs.message = match status {
+            JobStatus::Done => format!("Route #{} ready", id),
+            JobStatus::NotFound => format!("Route #{}: no path found", id),
+            JobStatus::Cancelled => format!("Route #{} cancelled", id),
+            JobStatus::Running => unreachable!(),
+        };
+    }
+}
 
-#[derive(Event)]
-struct DijkstraEvent(DijkstraUpdate);
+fn update_search_overlay(
+    mut event_reader: EventReader<DijkstraEvent>,
+    mut overlay: ResMut<SearchOverlay>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    for event in event_reader.read() {
+        match &event.0 {
+            DijkstraUpdate::Job(_, JobStatus::Running) => {
+                let image = images.get_mut(&overlay.image).unwrap();
+                image.data.fill(0);
+                overlay.frontier.clear();
+            }
+            DijkstraUpdate::Search(SearchSnapshot { visited, frontier }) => {
+                let image = images.get_mut(&overlay.image).unwrap();
+                let mut paint = |&(row, col): &(usize, usize), color: [u8; 4]| {
+                    let pixel = image
+                        .pixel_bytes_mut(UVec3::new(col as u32, row as u32, 0))
+                        .unwrap();
+                    pixel.copy_from_slice(&color);
+                };
+                for cell in overlay.frontier.iter() {
+                    paint(cell, [0; 4]);
+                }
+                for cell in visited.iter() {
+                    paint(cell, VISITED_COLOR);
+                }
+                for cell in frontier.iter() {
+                    paint(cell, FRONTIER_COLOR);
+                }
+                overlay.frontier.clone_from(frontier);
+            }
+            _ => {}
+        }
+    }
+}
 
-#[derive(Resource)]
-struct DijkstraCommandHolder(DijkstraCommand);
+fn show_alternatives(
+    mut commands: Commands,
+    mut event_reader: EventReader<DijkstraEvent>,
+    mut proposal: ResMut<RouteProposal>,
+    mut images: ResMut<Assets<Image>>,
+    panel: Single<Entity, With<AlternativesPanel>>,
+) {
+    for event in event_reader.read() {
+        let DijkstraEvent(DijkstraUpdate::Alternatives(alternatives)) = event else {
+            continue;
+        };
+        let image = images.get_mut(&proposal.preview).unwrap();
+        image.data.fill(0);
+        commands.entity(*panel).despawn_descendants();
+        // Drawn in reverse so that the best route ends up on top.
+        for (i, option) in alternatives.options.iter().enumerate().rev() {
+            let color = ALTERNATIVE_COLORS[i];
+            for &(start, end) in option.path.iter() {
+                for (row, col) in segment_cells(start, end) {
+                    let pixel = image
+                        .pixel_bytes_mut(UVec3::new(col as u32, row as u32, 0))
+                        .unwrap();
+                    pixel.copy_from_slice(&color);
+                }
+            }
+        }
+        for (i, option) in alternatives.options.iter().enumerate() {
+            let color = ALTERNATIVE_COLORS[i];
+            commands.entity(*panel).with_child((
+                Text::new(format!(
+                    "[{}] {}",
+                    i + 1,
+                    format_breakdown(&option.breakdown)
+                )),
+                TextFont {
+                    font_size: 14.0,
+                    ..default()
+                },
+                TextColor(Color::srgb_u8(color[0], color[1], color[2])),
+            ));
+        }
+        proposal.alternatives = Some(alternatives.clone());
+    }
+}
 
-#[derive(Resource)]
-struct DijkstraCommandSender(Sender<DijkstraCommand>);
+fn format_breakdown(breakdown: &RouteBreakdown) -> String {
+    format!(
+        "length {:.2} km\n existing {:.2} km\n new track {:.2} km\n bridges {:.2} km\n\
+         climb {:.0} m\ndescent {:.0} m\nmax grade {:.1}%\n\
+         cost {:.0}\n existing {:.0}\n new track {:.0}\n bridges {:.0}\n climbing {:.0}\n\
+         price {}, {} per km",
+        breakdown.length,
+        breakdown.existing_length,
+        breakdown.new_length,
+        breakdown.bridge_length,
+        breakdown.climb,
+        breakdown.descent,
+        breakdown.max_grade,
+        breakdown.total_cost(),
+        breakdown.existing_cost,
+        breakdown.new_track_cost,
+        breakdown.bridge_cost,
+        breakdown.climb_cost,
+        format_money(route_price(breakdown)),
+        format_money(route_price(breakdown) / breakdown.length.max(0.01)),
+    )
+}
 
-fn read_dijkstra_stream(
-    dijkstra_receiver: Res<DijkstraReceiver>,
-    mut event_writer: EventWriter<DijkstraEvent>,
+#[allow(clippy::too_many_arguments)]
+fn pick_alternative(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut proposal: ResMut<RouteProposal>,
+    mut images: ResMut<Assets<Image>>,
+    mut route_jobs: ResMut<RouteJobs>,
+    panel: Single<Entity, With<AlternativesPanel>>,
+    mut commit_writer: EventWriter<CommitRoute>,
+    economy: Res<Economy>,
 ) {
-    if let Ok(path) = dijkstra_receiver.try_recv() {
-        event_writer.send(DijkstraEvent(path));
+    let Some(alternatives) = proposal.alternatives.as_ref() else {
+        return;
+    };
+    let Some(index) = ALTERNATIVE_KEYS
+        .iter()
+        .position(|key| keys.just_pressed(*key))
+        .filter(|&index| index < alternatives.options.len())
+    else {
+        return;
+    };
+    let option = &alternatives.options[index];
+    let price = route_price(&option.breakdown) + vehicle_price(option.mode);
+    if price > economy.treasury {
+        route_jobs.message = format!(
+            "Cannot afford alternative {}: costs {} with its vehicle, only {} in the treasury",
+            index + 1,
+            format_money(price),
+            format_money(economy.treasury)
+        );
+        return;
     }
+    commit_writer.send(CommitRoute(alternatives.options[index].clone()));
+    route_jobs.message = format!(
+        "Route #{}: built alternative {}",
+        alternatives.id,
+        index + 1
+    );
+    proposal.alternatives = None;
+    images.get_mut(&proposal.preview).unwrap().data.fill(0);
+    commands.entity(*panel).despawn_descendants();
 }
 
-fn process_dijkstra_updates(
+fn discard_alternatives(
     mut commands: Commands,
-    mut event_reader: EventReader<DijkstraEvent>,
-    image_handle: Res<ImageHandle>,
+    mut proposal: ResMut<RouteProposal>,
     mut images: ResMut<Assets<Image>>,
-    win_entity: Single<Entity, With<Window>>,
-    mut map_state: ResMut<MapState>,
+    route_jobs: Res<RouteJobs>,
+    panel: Single<Entity, With<AlternativesPanel>>,
 ) {
-    for event in event_reader.read() {
-        let image = images.get_mut(&image_handle.0).unwrap();
-        commands
-            .entity(*win_entity)
-            .insert(CursorIcon::from(SystemCursorIcon::Default));
-        let path = map_state.process_dijsktra_update(&event.0, image);
-
-        // create a train that moves along the path
-        let train_sprite_img = Image::new_fill(
-            Extent3d {
-                width: 5 as u32,
-                height: 5 as u32,
-                depth_or_array_layers: 1,
-            },
-            TextureDimension::D2,
-            &Srgba::new(0.9, 0.9, 0.9, 1.0).to_u8_array(),
-            TextureFormat::Rgba8UnormSrgb,
-            RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    // Escape cancels the running search first.
+    if route_jobs.running.is_some() || proposal.alternatives.is_none() {
+        return;
+    }
+    proposal.alternatives = None;
+    images.get_mut(&proposal.preview).unwrap().data.fill(0);
+    commands.entity(*panel).despawn_descendants();
+}
+
+// Marks the suitability map stale when the map changes. While the overlay
+// shows it, it is computed again once a brush stroke is over, and no more
+// often than every few seconds while towns grow.
+fn refresh_stale_suitability(
+    map_state: Res<MapState>,
+    mut layer: ResMut<SuitabilityLayer>,
+    mut images: ResMut<Assets<Image>>,
+    visibility: Single<&Visibility, With<SuitabilityOverlaySprite>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    time: Res<Time>,
+) {
+    layer.since_refresh += time.delta_secs();
+    if map_state.is_changed() && layer.map.is_some() {
+        layer.stale = true;
+    }
+    if !layer.stale
+        || **visibility == Visibility::Hidden
+        || mouse.pressed(MouseButton::Left)
+        || layer.since_refresh < SUITABILITY_REFRESH_INTERVAL
+    {
+        return;
+    }
+    refresh_suitability(&map_state, &mut layer, &mut images);
+}
+
+// While the overlay is shown, clicks explain suitability instead of picking
+// stations.
+fn suitability_shown(visibility: Query<&Visibility, With<SuitabilityOverlaySprite>>) -> bool {
+    visibility
+        .iter()
+        .any(|visibility| *visibility != Visibility::Hidden)
+}
+
+// Computes the suitability map if the map changed since it was last
+// drawn, and paints it from red for the worst cells to green for the best.
+fn refresh_suitability(
+    map_state: &MapState,
+    layer: &mut SuitabilityLayer,
+    images: &mut Assets<Image>,
+) {
+    if layer.map.is_some() && !layer.stale {
+        return;
+    }
+    let map = Suitability::compute(&map_state.dijkstra, &map_state.network);
+    let image = images.get_mut(&layer.image).unwrap();
+    for row in 0..map.height {
+        for col in 0..map.width {
+            let score = map.score((row, col));
+            let color = if score == 0.0 {
+                [0; 4]
+            } else {
+                [
+                    (510.0 * (1.0 - score)).min(255.0) as u8,
+                    (510.0 * score).min(255.0) as u8,
+                    0,
+                    140,
+                ]
+            };
+            image
+                .pixel_bytes_mut(UVec3::new(col as u32, row as u32, 0))
+                .unwrap()
+                .copy_from_slice(&color);
+        }
+    }
+    layer.map = Some(map);
+    layer.stale = false;
+    layer.since_refresh = 0.0;
+}
+
+fn toggle_suitability_overlay(
+    map_state: Res<MapState>,
+    mut layer: ResMut<SuitabilityLayer>,
+    mut images: ResMut<Assets<Image>>,
+    mut visibility: Single<&mut Visibility, With<SuitabilityOverlaySprite>>,
+) {
+    if **visibility == Visibility::Hidden {
+        refresh_suitability(&map_state, &mut layer, &mut images);
+    }
+    visibility.toggle_visible_hidden();
+}
+
+// Marks the most suitable sites away from existing stations on the overlay
+// and lists them.
+fn suggest_sites(
+    map_state: Res<MapState>,
+    mut layer: ResMut<SuitabilityLayer>,
+    mut images: ResMut<Assets<Image>>,
+    mut visibility: Single<&mut Visibility, With<SuitabilityOverlaySprite>>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    refresh_suitability(&map_state, &mut layer, &mut images);
+    let map = layer.map.as_ref().unwrap();
+    let stations = map_state
+        .network
+        .stations
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    let sites = map.best_sites(SUGGESTED_SITES, SITE_SPACING, &stations);
+    let image = images.get_mut(&layer.image).unwrap();
+    for &(row, col) in sites.iter() {
+        println!(
+            "Suggested site {:?}: score {:.2}, {:?}",
+            (row, col),
+            map.score((row, col)),
+            map.factors[row][col]
         );
-        let train_sprite_handle = images.add(train_sprite_img).clone();
-        commands.spawn((Sprite::from_image(train_sprite_handle), Train::new(path)));
+        for r in row.saturating_sub(3)..(row + 4).min(map.height) {
+            for c in col.saturating_sub(3)..(col + 4).min(map.width) {
+                if r.abs_diff(row).pow(2) + c.abs_diff(col).pow(2) <= 9 {
+                    image
+                        .pixel_bytes_mut(UVec3::new(c as u32, r as u32, 0))
+                        .unwrap()
+                        .copy_from_slice(&SITE_COLOR);
+                }
+            }
+        }
+    }
+    route_jobs.message = format!("Best sites: {:?}", sites);
+    **visibility = Visibility::Visible;
+}
+
+// Clicking a cell while the overlay is shown explains its suitability.
+fn query_suitability(
+    query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: Query<&Window>,
+    map_state: Res<MapState>,
+    layer: Res<SuitabilityLayer>,
+    mut route_jobs: ResMut<RouteJobs>,
+) {
+    let (Some((row, col)), Some(map)) = (
+        cursor_cell(&query, &windows, &map_state),
+        layer.map.as_ref(),
+    ) else {
+        return;
+    };
+    let factors = map.factors[row][col];
+    route_jobs.message = format!(
+        "Suitability of {:?}: {:.2} (flatness {:.2}, water {:.2}, altitude {:.2}, access {:.2}, {:?})",
+        (row, col),
+        map.score((row, col)),
+        factors.flatness,
+        factors.water,
+        factors.altitude,
+        factors.access,
+        factors.biome
+    );
+}
+
+fn toggle_search_overlay(mut visibility: Single<&mut Visibility, With<SearchOverlaySprite>>) {
+    visibility.toggle_visible_hidden();
+}
+
+fn show_route_status(
+    mut commands: Commands,
+    route_jobs: Res<RouteJobs>,
+    economy: Res<Economy>,
+    transport_mode: Res<TransportMode>,
+    win_entity: Single<Entity, With<Window>>,
+    mut status_text: Single<&mut Text, With<RouteStatusText>>,
+) {
+    if !route_jobs.is_changed() && !economy.is_changed() && !transport_mode.is_changed() {
+        return;
+    }
+    let icon = if route_jobs.pending() > 0 {
+        SystemCursorIcon::Progress
+    } else {
+        SystemCursorIcon::Default
+    };
+    commands.entity(*win_entity).insert(CursorIcon::from(icon));
+    let mut status = vec![format!("{:?}", transport_mode.0)];
+    if let Some(id) = route_jobs.running {
+        status.push(format!("Route #{} searching", id));
     }
+    if !route_jobs.queued.is_empty() {
+        status.push(format!("{} queued", route_jobs.queued.len()));
+    }
+    if !route_jobs.message.is_empty() {
+        status.push(route_jobs.message.clone());
+    }
+    status_text.0 = format!("{}\n{}", status.join(" | "), economy.summary());
+}
+
+fn cancel_route_jobs(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut route_jobs: ResMut<RouteJobs>,
+    dijkstra_command_sender: Res<DijkstraCommandSender>,
+) {
+    let command = if keys.pressed(KeyCode::ShiftLeft) || keys.pressed(KeyCode::ShiftRight) {
+        DijkstraCommand::CancelAll
+    } else if let Some(id) = route_jobs.running {
+        DijkstraCommand::Cancel(id)
+    } else {
+        return;
+    };
+    match dijkstra_command_sender.0.try_send(command) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            route_jobs.message = "Route worker busy, try again".to_string();
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            route_jobs.message = "Route worker stopped".to_string();
+        }
+    }
+}
+
+fn signal_trains(
+    map_state: Res<MapState>,
+    mut signals: ResMut<Signals>,
+    mut train_query: Query<(Entity, &mut Train)>,
+    mut route_jobs: ResMut<RouteJobs>,
+    time: Res<Time>,
+) {
+    let mut trains = train_query.iter_mut().collect::<Vec<_>>();
+    if !signals.update(
+        &map_state.graph,
+        &map_state.network,
+        &mut trains,
+        time.delta_secs(),
+    ) {
+        return;
+    }
+    for deadlock in signals.deadlocks.iter() {
+        println!("Deadlock between trains {:?}", deadlock);
+    }
+    route_jobs.message = match signals.deadlocks.len() {
+        0 => "Deadlock cleared".to_string(),
+        count => format!("{} deadlock(s) between trains, see log", count),
+    };
 }
 
-fn update_trains(
-    map_state: ResMut<MapState>,
-    mut train_query: Query<(&mut Transform, &mut Train)>,
+// Lets the next held train of each line leave its first station when its
+// departure slot comes up.
+fn dispatch_lines(
+    mut lines: ResMut<Lines>,
+    mut train_query: Query<(Entity, &mut Train)>,
+    time: Res<Time>,
 ) {
-    for (mut transform, mut train) in &mut train_query {
-        if train.index == (train.path.len() - 1) && train.forward {
-            train.forward = false;
+    let now = time.elapsed_secs();
+    let mut trains = train_query
+        .iter_mut()
+        .filter(|(_, train)| train.held)
+        .collect::<Vec<_>>();
+    trains.sort_by_key(|(entity, _)| *entity);
+    for (id, line) in lines.lines.iter_mut() {
+        if now < line.next_departure {
             continue;
-        } else if train.index == 0 && !train.forward {
-            train.forward = true;
+        }
+        if let Some((_, train)) = trains
+            .iter_mut()
+            .find(|(_, train)| train.held && train.line == Some(*id))
+        {
+            train.held = false;
+            // Board whoever has turned up while it waited.
+            train.arrived = Some(train.path[0]);
+            line.next_departure = line.schedule.after(now);
+        }
+    }
+}
+
+fn generate_demand(map_state: Res<MapState>, mut demand: ResMut<Demand>, time: Res<Time>) {
+    demand.generate(&map_state, time.delta_secs());
+}
+
+fn run_trains(mut economy: ResMut<Economy>, trains: Query<&Train>, time: Res<Time>) {
+    economy.run(trains.iter().count(), time.delta_secs());
+}
+
+// Trains that just pulled in at a station unload and load there, and the
+// trips they deliver are paid for.
+fn load_trains(
+    map_state: Res<MapState>,
+    mut demand: ResMut<Demand>,
+    mut economy: ResMut<Economy>,
+    mut train_query: Query<&mut Train>,
+) {
+    for mut train in &mut train_query {
+        let Some(station) = train.arrived.take() else {
             continue;
+        };
+        if map_state.network.stations.contains(&station) {
+            let revenue = demand.call(station, &mut train);
+            economy.earn(revenue);
         }
-        if train.forward {
-            train.index += 1;
-        } else {
-            train.index = train.index.saturating_sub(1);
+    }
+}
+
+fn update_trains(map_state: Res<MapState>, mut train_query: Query<&mut Train>, time: Res<Time>) {
+    for mut train in &mut train_query {
+        let (row, col) = train.path[train.behind()];
+        let tier = map_state.network.tier(train.mode)[row][col];
+        train.advance(
+            time.delta_secs(),
+            &map_state.dijkstra.height_map,
+            &map_state.dijkstra.scale,
+            tier.speed(),
+        );
+    }
+}
+
+fn measure_traffic(
+    map_state: Res<MapState>,
+    mut traffic: ResMut<Traffic>,
+    train_query: Query<(Entity, &Train)>,
+    mut route_jobs: ResMut<RouteJobs>,
+    time: Res<Time>,
+) {
+    let trains = train_query.iter().collect::<Vec<_>>();
+    traffic.measure(&trains, time.delta_secs());
+    if !traffic.suggest(&map_state) {
+        return;
+    }
+    for upgrade in traffic.suggestions.iter() {
+        println!(
+            "Congested {} from {:?} to {:?}: {:.1} vehicles/min, capacity {:.0}",
+            upgrade.tier.name(upgrade.mode),
+            upgrade.cells[0],
+            upgrade.cells[upgrade.cells.len() - 1],
+            upgrade.flow,
+            capacity(upgrade.mode, upgrade.tier)
+        );
+    }
+    if !traffic.suggestions.is_empty() {
+        route_jobs.message = format!(
+            "{} upgrade(s) suggested, Shift+U to build the most congested",
+            traffic.suggestions.len()
+        );
+    }
+}
+
+// Raises the route under the cursor one tier, or with Shift the most
+// congested stretch the traffic count suggests.
+#[allow(clippy::too_many_arguments)]
+fn upgrade_infrastructure(
+    query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: Query<&Window>,
+    keys: Res<ButtonInput<KeyCode>>,
+    image_handle: Res<ImageHandle>,
+    mut images: ResMut<Assets<Image>>,
+    mut map_state: ResMut<MapState>,
+    mut traffic: ResMut<Traffic>,
+    mut route_jobs: ResMut<RouteJobs>,
+    mut history: ResMut<History>,
+    mut economy: ResMut<Economy>,
+) {
+    let (mode, cells) = if keys.pressed(KeyCode::ShiftLeft) || keys.pressed(KeyCode::ShiftRight) {
+        if traffic.suggestions.is_empty() {
+            route_jobs.message = "No upgrades suggested".to_string();
+            return;
         }
+        let upgrade = traffic.suggestions.remove(0);
+        (upgrade.mode, upgrade.cells)
+    } else {
+        let Some(cell) = cursor_cell(&query, &windows, &map_state) else {
+            return;
+        };
+        let Some(id) = map_state.network.route_near(cell, 2) else {
+            return;
+        };
+        let plan = &map_state.network.routes[&id];
+        (plan.mode, plan.cells())
+    };
+    let cost = map_state
+        .dijkstra
+        .upgrade_cost(&map_state.network, mode, &cells);
+    if cost == 0.0 {
+        route_jobs.message = "Already at the highest tier".to_string();
+        return;
+    }
+    let construction = upgrade_price(cost);
+    if let Err(reason) = economy.buy(construction, 0.0) {
+        route_jobs.message = format!("Cannot upgrade: {}", reason);
+        return;
+    }
+    let image = images.get_mut(&image_handle.0).unwrap();
+    let edit = map_state.upgrade(mode, &cells, image);
+    route_jobs.message = format!(
+        "Upgraded {} cells for {}",
+        edit.network.tiers.len(),
+        format_money(construction)
+    );
+    history.record(HistoryEntry {
+        edit,
+        spawned: Vec::new(),
+        despawned: Vec::new(),
+        lines: Vec::new(),
+        construction,
+        purchases: 0.0,
+    });
+}
 
-        transform.translation.x =
-            train.path[train.index].1 as f32 - (map_state.dijkstra.width as f32) * 0.5;
-        transform.translation.y =
-            -1.0 * train.path[train.index].0 as f32 + (map_state.dijkstra.height as f32) * 0.5;
-        transform.translation.z = 10.0;
+// Lines each car up along the track behind its locomotive, moving it on
+// smoothly between the fixed steps that advance trains.
+fn place_trains(
+    map_state: Res<MapState>,
+    train_query: Query<(&Train, &Children)>,
+    mut car_query: Query<(&Car, &mut Transform)>,
+    time: Res<Time<Fixed>>,
+) {
+    let overstep = time.overstep_fraction();
+    for (train, children) in &train_query {
+        let head = train.shown_distance(overstep);
+        for &child in children.iter() {
+            let Ok((car, mut transform)) = car_query.get_mut(child) else {
+                continue;
+            };
+            let (row, col, angle) = train.position_at(train.car_distance(head, car.0));
+            transform.translation.x = col - (map_state.dijkstra.width as f32) * 0.5;
+            transform.translation.y = -row + (map_state.dijkstra.height as f32) * 0.5;
+            transform.rotation = Quat::from_rotation_z(angle);
+        }
     }
 }
 
@@ -145,16 +1881,17 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>, map_state: R
 
     let image_handle = images.add(image).clone();
 
-    let (tx, rx) = bounded::<DijkstraUpdate>(1);
-    let (tx_command, rx_command) = bounded::<DijkstraCommand>(1);
+    let (tx, rx) = bounded::<DijkstraUpdate>(16);
+    let (tx_command, rx_command) = bounded::<DijkstraCommand>(MAX_PENDING_JOBS);
     commands.insert_resource(DijkstraCommandSender(tx_command));
-    commands.insert_resource(DijkstraCommandHolder(DijkstraCommand {
+    commands.insert_resource(DijkstraCommandHolder {
         a: (0, 0),
         b: (0, 0),
-    }));
+    });
+    commands.insert_resource(RouteJobs::default());
+    commands.insert_resource(History::default());
     let mut other_dijkstra = map_state.dijkstra.clone();
     std::thread::spawn(move || {
-        // other_dijkstra.connect_randoms_forever(tx);
         other_dijkstra.connect_selected(&rx_command, tx);
     });
     commands.insert_resource(DijkstraReceiver(rx));
@@ -164,7 +1901,198 @@ fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>, map_state: R
 
     commands.spawn(Sprite::from_image(image_handle));
 
+    let train_sprite_img = Image::new_fill(
+        Extent3d {
+            width: 5,
+            height: 5,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &Srgba::new(0.9, 0.9, 0.9, 1.0).to_u8_array(),
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    commands.insert_resource(TrainSprite(images.add(train_sprite_img)));
+
+    let overlay_image = Image::new_fill(
+        Extent3d {
+            width: map_state.dijkstra.width as u32,
+            height: map_state.dijkstra.height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    let overlay_handle = images.add(overlay_image);
+    commands.spawn((
+        Sprite::from_image(overlay_handle.clone()),
+        Transform::from_xyz(0.0, 0.0, 5.0),
+        SearchOverlaySprite,
+    ));
+    commands.insert_resource(SearchOverlay {
+        image: overlay_handle,
+        frontier: Vec::new(),
+    });
+
+    let suitability_image = Image::new_fill(
+        Extent3d {
+            width: map_state.dijkstra.width as u32,
+            height: map_state.dijkstra.height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    let suitability_handle = images.add(suitability_image);
+    commands.spawn((
+        Sprite::from_image(suitability_handle.clone()),
+        Transform::from_xyz(0.0, 0.0, 4.0),
+        Visibility::Hidden,
+        SuitabilityOverlaySprite,
+    ));
+    commands.insert_resource(SuitabilityLayer {
+        map: None,
+        image: suitability_handle,
+        stale: false,
+        since_refresh: 0.0,
+    });
+
+    let trail_image = Image::new_fill(
+        Extent3d {
+            width: map_state.dijkstra.width as u32,
+            height: map_state.dijkstra.height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    let trail_handle = images.add(trail_image);
+    commands.spawn((
+        Sprite::from_image(trail_handle.clone()),
+        Transform::from_xyz(0.0, 0.0, 3.0),
+        Visibility::Hidden,
+        TrailOverlaySprite,
+    ));
+    commands.insert_resource(TrailOverlay {
+        image: trail_handle,
+        painted: Vec::new(),
+        since_paint: 0.0,
+        since_report: 0.0,
+    });
+
+    let mut zone_image = Image::new_fill(
+        Extent3d {
+            width: map_state.dijkstra.width as u32,
+            height: map_state.dijkstra.height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    for (row, zones) in map_state.network.zones.iter().enumerate() {
+        for (col, &zone) in zones.iter().enumerate() {
+            if zone != Zone::Free {
+                paint_zone(&mut zone_image, (row, col), zone);
+            }
+        }
+    }
+    let zone_handle = images.add(zone_image);
+    commands.spawn((
+        Sprite::from_image(zone_handle.clone()),
+        Transform::from_xyz(0.0, 0.0, 2.0),
+    ));
+    commands.insert_resource(ZoneLayer {
+        brush: None,
+        unsaved: false,
+        image: zone_handle,
+    });
+
+    let preview_image = Image::new_fill(
+        Extent3d {
+            width: map_state.dijkstra.width as u32,
+            height: map_state.dijkstra.height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    let preview_handle = images.add(preview_image);
+    commands.spawn((
+        Sprite::from_image(preview_handle.clone()),
+        Transform::from_xyz(0.0, 0.0, 6.0),
+    ));
+    commands.insert_resource(RouteProposal {
+        alternatives: None,
+        preview: preview_handle,
+    });
+
     commands.spawn((Camera2d, MainCamera));
+
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: 16.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        },
+        RouteStatusText,
+    ));
+
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: 14.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            right: Val::Px(5.0),
+            ..default()
+        },
+        RouteReportText,
+    ));
+
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: 14.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            right: Val::Px(5.0),
+            ..default()
+        },
+        DeparturesBoardText,
+    ));
+
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            left: Val::Px(5.0),
+            column_gap: Val::Px(20.0),
+            ..default()
+        },
+        AlternativesPanel,
+    ));
 }
 
 fn pan_camera(
@@ -178,58 +2106,98 @@ fn pan_camera(
     }
 }
 
+fn cursor_cell(
+    query: &Query<(&GlobalTransform, &Camera, &MainCamera)>,
+    windows: &Query<&Window>,
+    state: &MapState,
+) -> Option<(usize, usize)> {
+    let (global_transform, camera, _) = query.single();
+    let cursor_position = windows.single().cursor_position()?;
+    let ray = camera
+        .viewport_to_world_2d(global_transform, cursor_position)
+        .ok()?;
+    let x = (ray.x + state.dijkstra.width as f32 / 2.0)
+        .clamp(0.0, state.dijkstra.width as f32 - 1.0) as usize;
+    let y = (-ray.y + state.dijkstra.height as f32 / 2.0)
+        .clamp(0.0, state.dijkstra.height as f32 - 1.0) as usize;
+    Some((y, x))
+}
+
 fn on_mouse_left_click(
     query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
     windows: Query<&Window>,
     state: Res<MapState>,
     mut dijkstra_command_holder: ResMut<DijkstraCommandHolder>,
+    mut lines: ResMut<Lines>,
+    mut route_jobs: ResMut<RouteJobs>,
 ) {
-    let (global_transform, camera, _) = query.single();
-    let window = windows.single();
-    let cursor_position = window.cursor_position().unwrap();
-    if let Ok(ray) = camera.viewport_to_world_2d(global_transform, cursor_position) {
-        let x = (ray.x + state.dijkstra.width as f32 / 2.0)
-            .clamp(0.0, state.dijkstra.width as f32 - 1.0) as usize;
-        let y = (-ray.y + state.dijkstra.height as f32 / 2.0)
-            .clamp(0.0, state.dijkstra.height as f32 - 1.0) as usize;
-        let station = state.near_station(y, x).unwrap_or((y, x));
-        if state.dijkstra.is_water[station.0][station.1] {
-            return;
+    let Some((y, x)) = cursor_cell(&query, &windows, &state) else {
+        return;
+    };
+    if let (Some(draft), Some(station)) = (lines.draft.as_mut(), state.near_station(y, x)) {
+        if draft.last() != Some(&station) {
+            draft.push(station);
         }
-        dijkstra_command_holder.0.a = station;
+        route_jobs.message = format!("New line: {} stations, Enter to create", draft.len());
+    }
+    let station = state.near_station(y, x).unwrap_or((y, x));
+    if state.dijkstra.is_water[station.0][station.1] {
+        return;
     }
+    dijkstra_command_holder.a = station;
 }
 
+#[allow(clippy::too_many_arguments)]
 fn on_mouse_right_click(
-    mut commands: Commands,
     query: Query<(&GlobalTransform, &Camera, &MainCamera)>,
     windows: Query<&Window>,
-    win_entity: Single<Entity, With<Window>>,
+    keys: Res<ButtonInput<KeyCode>>,
     state: Res<MapState>,
     mut dijkstra_command_holder: ResMut<DijkstraCommandHolder>,
     dijkstra_command_sender: Res<DijkstraCommandSender>,
+    transport_mode: Res<TransportMode>,
+    mut route_jobs: ResMut<RouteJobs>,
 ) {
-    let (global_transform, camera, _) = query.single();
-    let window = windows.single();
-    let cursor_position = window.cursor_position().unwrap();
-    if let Ok(ray) = camera.viewport_to_world_2d(global_transform, cursor_position) {
-        let x = (ray.x + state.dijkstra.width as f32 / 2.0)
-            .clamp(0.0, state.dijkstra.width as f32 - 1.0) as usize;
-        let y = (-ray.y + state.dijkstra.height as f32 / 2.0)
-            .clamp(0.0, state.dijkstra.height as f32 - 1.0) as usize;
-        let station = state.near_station(y, x).unwrap_or((y, x));
-        if state.dijkstra.is_water[station.0][station.1] {
-            return;
+    let Some((y, x)) = cursor_cell(&query, &windows, &state) else {
+        return;
+    };
+    let station = state.near_station(y, x).unwrap_or((y, x));
+    if state.dijkstra.is_water[station.0][station.1] {
+        return;
+    }
+    dijkstra_command_holder.b = station;
+    // Superseding replaces everything in flight, so it is never refused.
+    let supersede = keys.pressed(KeyCode::ShiftLeft) || keys.pressed(KeyCode::ShiftRight);
+    if !supersede && route_jobs.pending() >= MAX_PENDING_JOBS {
+        route_jobs.message = format!("Route queue full ({} jobs)", MAX_PENDING_JOBS);
+        return;
+    }
+    let job = RouteJob {
+        id: route_jobs.next_id,
+        a: dijkstra_command_holder.a,
+        b: dijkstra_command_holder.b,
+        mode: transport_mode.0,
+        network: state.network.clone(),
+    };
+    let command = if supersede {
+        DijkstraCommand::Supersede(job)
+    } else {
+        DijkstraCommand::Connect(job)
+    };
+    let id = route_jobs.next_id;
+    match dijkstra_command_sender.0.try_send(command) {
+        Ok(()) => {
+            route_jobs.next_id += 1;
+            route_jobs.queued.push(id);
+            route_jobs.message = format!("Route #{} queued", id);
+        }
+        Err(TrySendError::Full(_)) => {
+            route_jobs.message = "Route worker busy, try again".to_string();
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            route_jobs.message = "Route worker stopped".to_string();
         }
-        dijkstra_command_holder.0.b = station;
     }
-    let _ = dijkstra_command_sender
-        .0
-        .send(dijkstra_command_holder.0.clone())
-        .unwrap();
-    commands
-        .entity(*win_entity)
-        .insert(CursorIcon::from(SystemCursorIcon::Progress));
 }
 
 fn zoom_camera_around_cursor(
@@ -260,3 +2228,80 @@ fn zoom_out(mut query: Query<(&mut Transform, &MainCamera)>) {
     transform.0.scale.x *= 1.1;
     transform.0.scale.y *= 1.1;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    // Swaps the trains of an entry back, as undo does, or forwards, as redo
+    // and the original edit do.
+    fn swap(world: &mut World, entry: HistoryEntry, undo: bool) -> HistoryEntry {
+        world
+            .run_system_once_with(
+                entry,
+                move |In(mut entry): In<HistoryEntry>,
+                      mut commands: Commands,
+                      train_sprite: Res<TrainSprite>,
+                      trains: Query<(Entity, &TrainId, &Train)>| {
+                    if undo {
+                        swap_trains(
+                            &mut commands,
+                            &train_sprite,
+                            &trains,
+                            &mut entry.spawned,
+                            &entry.despawned,
+                        );
+                    } else {
+                        swap_trains(
+                            &mut commands,
+                            &train_sprite,
+                            &trains,
+                            &mut entry.despawned,
+                            &entry.spawned,
+                        );
+                    }
+                    entry
+                },
+            )
+            .unwrap()
+    }
+
+    fn entry(spawned: Vec<(TrainId, Train)>, despawned: Vec<(TrainId, Train)>) -> HistoryEntry {
+        HistoryEntry {
+            edit: Edit::default(),
+            spawned,
+            despawned,
+            lines: Vec::new(),
+            construction: 0.0,
+            purchases: 0.0,
+        }
+    }
+
+    fn train_count(world: &mut World) -> usize {
+        world.query::<&TrainId>().iter(world).count()
+    }
+
+    #[test]
+    fn undo_build_after_undoing_demolish_removes_restored_train() {
+        let mut world = World::new();
+        world.insert_resource(TrainSprite(Handle::default()));
+        let train = Train::new(vec![(0, 0), (0, 1), (0, 2)]);
+        let id = TrainId(1);
+        let build = swap(
+            &mut world,
+            entry(vec![(id, train.clone())], Vec::new()),
+            false,
+        );
+        let demolish = swap(&mut world, entry(Vec::new(), vec![(id, train)]), false);
+        assert_eq!(train_count(&mut world), 0);
+        let demolish = swap(&mut world, demolish, true);
+        assert_eq!(train_count(&mut world), 1);
+        let build = swap(&mut world, build, true);
+        assert_eq!(train_count(&mut world), 0);
+        swap(&mut world, build, false);
+        assert_eq!(train_count(&mut world), 1);
+        swap(&mut world, demolish, false);
+        assert_eq!(train_count(&mut world), 0);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::network::Mode;
+use crate::state::MapState;
+use bevy::prelude::*;
+use rand::prelude::*;
+use std::collections::{HashMap, VecDeque};
+
+// Walkers on the map at once.
+pub const WALKERS: usize = 50;
+// Wear each step leaves on a cell, and the wear at which a cell counts as
+// half trodden.
+const WEAR_PER_STEP: f32 = 1.0;
+pub const HALF_WEAR: f32 = 20.0;
+// How much cheaper a fully trodden cell is to step on.
+const WEAR_DISCOUNT: f32 = 0.8;
+// Seconds over which wear fades, and below which it is forgotten.
+const WEAR_LIFETIME: f32 = 60.0;
+const MIN_WEAR: f32 = 0.05;
+// Cost per cell still to go, pulling walkers towards their destination.
+const DIRECTNESS: f32 = 2.0;
+// Cells a walker remembers and will not step back on.
+const MEMORY: usize = 8;
+// Steps after which a walker that has not arrived gives up.
+const MAX_STEPS: usize = 4000;
+
+// Someone walking from station to station along the easiest way they see.
+pub struct Walker {
+    pub cell: (usize, usize),
+    pub destination: (usize, usize),
+    recent: VecDeque<(usize, usize)>,
+    steps: usize,
+}
+
+// Walkers wearing trails into the land, and how worn each cell is.
+#[derive(Resource, Default)]
+pub struct Walkers {
+    pub active: bool,
+    pub walkers: Vec<Walker>,
+    pub wear: HashMap<(usize, usize), f32>,
+}
+
+impl Walkers {
+    // How trodden a cell is, from 0 for untouched towards 1.
+    pub fn trodden(&self, cell: (usize, usize)) -> f32 {
+        let wear = self.wear.get(&cell).cloned().unwrap_or(0.0);
+        wear / (wear + HALF_WEAR)
+    }
+
+    // Moves every walker one cell, to the neighbour where the step there and
+    // the straight way on from it cost least, wear making trodden cells
+    // cheaper. Arrived walkers set off again for another station.
+    pub fn step(&mut self, map_state: &MapState, rng: &mut impl Rng) {
+        let mut stations = map_state
+            .network
+            .stations
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        if stations.len() < 2 {
+            self.walkers.clear();
+            return;
+        }
+        stations.sort();
+        self.walkers
+            .retain(|walker| map_state.network.stations.contains(&walker.destination));
+        while self.walkers.len() < WALKERS {
+            let &start = stations.choose(rng).unwrap();
+            self.walkers.push(Walker {
+                cell: start,
+                destination: start,
+                recent: VecDeque::new(),
+                steps: 0,
+            });
+        }
+        let dijkstra = &map_state.dijkstra;
+        let mut walkers = std::mem::take(&mut self.walkers);
+        for walker in walkers.iter_mut() {
+            if walker.cell == walker.destination || walker.steps > MAX_STEPS {
+                let from = walker.cell;
+                walker.destination = *stations
+                    .iter()
+                    .filter(|&&station| station != from)
+                    .choose(rng)
+                    .unwrap();
+                walker.recent.clear();
+                walker.steps = 0;
+            }
+            let (row, col) = walker.cell;
+            let mut best = None;
+            let mut best_cost = f32::MAX;
+            for r in row.saturating_sub(1)..(row + 2).min(dijkstra.height) {
+                for c in col.saturating_sub(1)..(col + 2).min(dijkstra.width) {
+                    if (r, c) == walker.cell
+                        || walker.recent.contains(&(r, c))
+                        || map_state.network.is_forbidden((r, c))
+                    {
+                        continue;
+                    }
+                    let (_, base_cost, climb_cost) =
+                        dijkstra.step_cost(&map_state.network, Mode::Road, walker.cell, (r, c));
+                    let discount = 1.0 - WEAR_DISCOUNT * self.trodden((r, c));
+                    let to_go = ((r.abs_diff(walker.destination.0).pow(2)
+                        + c.abs_diff(walker.destination.1).pow(2))
+                        as f32)
+                        .sqrt();
+                    let cost = (base_cost + climb_cost) * discount + DIRECTNESS * to_go;
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best = Some((r, c));
+                    }
+                }
+            }
+            let Some(next) = best else {
+                walker.recent.clear();
+                continue;
+            };
+            walker.recent.push_back(walker.cell);
+            if walker.recent.len() > MEMORY {
+                walker.recent.pop_front();
+            }
+            walker.cell = next;
+            walker.steps += 1;
+            *self.wear.entry(next).or_default() += WEAR_PER_STEP;
+        }
+        self.walkers = walkers;
+    }
+
+    pub fn decay(&mut self, seconds: f32) {
+        let fade = (-seconds / WEAR_LIFETIME).exp();
+        self.wear.retain(|_, wear| {
+            *wear *= fade;
+            *wear > MIN_WEAR
+        });
+    }
+
+    // How many cells are well trodden, and what share of them the built
+    // network already covers: how closely the trails follow the plan.
+    pub fn overlap(&self, map_state: &MapState) -> (usize, f32) {
+        let network = &map_state.network;
+        let trails = self
+            .wear
+            .keys()
+            .filter(|&&cell| self.trodden(cell) > 0.5)
+            .collect::<Vec<_>>();
+        let built = trails
+            .iter()
+            .filter(|&&&(row, col)| {
+                network.rail_level[row][col] != 0 || network.road_level[row][col] != 0
+            })
+            .count();
+        let share = if trails.is_empty() {
+            0.0
+        } else {
+            built as f32 / trails.len() as f32
+        };
+        (trails.len(), share)
+    }
+}
@@ -0,0 +1,67 @@
+use std::fs;
+
+// How much more a step into a penalty zone costs.
+pub const ZONE_PENALTY: f32 = 4.0;
+
+// Areas planners keep routes out of: forbidden outright, or only at an
+// extra cost.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Zone {
+    #[default]
+    Free,
+    Penalty,
+    Forbidden,
+}
+
+impl Zone {
+    fn digit(self) -> char {
+        match self {
+            Zone::Free => '0',
+            Zone::Penalty => '1',
+            Zone::Forbidden => '2',
+        }
+    }
+
+    fn from_digit(digit: char) -> Option<Zone> {
+        match digit {
+            '0' => Some(Zone::Free),
+            '1' => Some(Zone::Penalty),
+            '2' => Some(Zone::Forbidden),
+            _ => None,
+        }
+    }
+}
+
+// Zones are saved next to the program, one file per map size since the
+// terrain is generated from a fixed seed.
+pub fn path(width: usize, height: usize) -> String {
+    format!("zones-{}x{}.txt", width, height)
+}
+
+// One line of digits per row.
+pub fn save(zones: &[Vec<Zone>], path: &str) -> std::io::Result<()> {
+    let text = zones
+        .iter()
+        .map(|row| row.iter().map(|zone| zone.digit()).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n");
+    fs::write(path, text)
+}
+
+// The saved zones, if there are any for a map of this size.
+pub fn load(path: &str, width: usize, height: usize) -> Option<Vec<Vec<Zone>>> {
+    let text = fs::read_to_string(path).ok()?;
+    let zones = text
+        .lines()
+        .map(|line| {
+            line.chars()
+                .map(Zone::from_digit)
+                .collect::<Option<Vec<_>>>()
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if zones.len() != height || zones.iter().any(|row| row.len() != width) {
+        println!("Ignoring {}: it is for a map of another size", path);
+        return None;
+    }
+    Some(zones)
+}